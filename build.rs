@@ -1,5 +1,9 @@
-extern crate capnpc;
-
 fn main() {
-    ::capnpc::CompilerCommand::new().file("src/api/Simulator.capnp").run().unwrap();
+    // the schema of the simulator servers is only used by the client
+    #[cfg(feature = "simserver")]
+    ::capnpc::CompilerCommand::new()
+        .file("src/api/Simulator.capnp")
+        .default_parent_module(vec!["simserver".into()])
+        .run()
+        .unwrap();
 }
@@ -1,19 +1,14 @@
-use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
-use std::net::ToSocketAddrs;
-use futures::AsyncReadExt;
-use futures::FutureExt;
 use amscircuit::*;
-use std::collections::HashMap;
+use amscircuit::simserver::Server;
+use amscircuit::waveform::{Dataset, Unit};
+use futures::AsyncReadExt;
+use std::net::ToSocketAddrs;
+use indexmap::IndexMap;
 use std::rc::Rc;
-use std::cell::{RefCell};
 use plotters::prelude::*;
 
-pub mod Simulator_capnp {
-  include!(concat!(env!("OUT_DIR"), "/src/api/Simulator_capnp.rs"));
-}
-
-fn plot(mut data: HashMap<String, Vec<f64>>) -> Result<(), Box<dyn std::error::Error>> {
-    let time = data.remove("time").unwrap();
+fn plot(data: Dataset) -> Result<(), Box<dyn std::error::Error>> {
+    let time = data.scale_values()?;
     let root =
         BitMapBackend::new("plot.png", (1024, 768)).into_drawing_area();
     root.fill(&WHITE)?;
@@ -43,9 +38,10 @@ fn plot(mut data: HashMap<String, Vec<f64>>) -> Result<(), Box<dyn std::error::E
         .y_label_formatter(&|x| format!("{:e}", x))
         .draw()?;
 
-    for ((key, val), color) in data.iter().zip(colorcycle) {
-       let series = LineSeries::new(time.clone().into_iter().zip(val.clone().into_iter()), color); 
-       if key.contains("#") || key.contains("@") {
+    let signals = data.signals().iter().filter_map(|(key, signal)| Some((key, signal.as_real()?)));
+    for ((key, val), color) in signals.filter(|(key, _)| *key != data.scale()).zip(colorcycle) {
+       let series = LineSeries::new(time.iter().copied().zip(val.iter().copied()), color);
+       if data.unit(key) == Some(Unit::Ampere) {
             chart.draw_secondary_series(series)?
                  .label(key)
                  .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
@@ -64,7 +60,7 @@ fn plot(mut data: HashMap<String, Vec<f64>>) -> Result<(), Box<dyn std::error::E
     Ok(())
 }
 
-fn circuit() -> String {
+fn circuit() -> Bundle {
     // PMOS transistor
     let code = CodeArch {
         reference: "m{{name}} {{port.d}} {{port.g}} {{port.s}} {{port.b}} PMOS W={{generic.w}} L={{generic.l}}".into(),
@@ -72,7 +68,7 @@ fn circuit() -> String {
     };
     let mut spicemos = CodeDialectArch::new();
     spicemos.dialects.insert("spice".into(), code);
-    let mut arches = HashMap::new();
+    let mut arches = IndexMap::new();
     arches.insert("rtl".into(), Arch::Code(spicemos));
 
     let pmos = Rc::from(Entity {
@@ -90,7 +86,7 @@ fn circuit() -> String {
     };
     let mut spicemos = CodeDialectArch::new();
     spicemos.dialects.insert("spice".into(), code);
-    let mut arches = HashMap::new();
+    let mut arches = IndexMap::new();
     arches.insert("rtl".into(), Arch::Code(spicemos));
 
     let nmos = Rc::from(Entity {
@@ -108,13 +104,13 @@ fn circuit() -> String {
     };
     let mut spicemos = CodeDialectArch::new();
     spicemos.dialects.insert("spice".into(), code);
-    let mut arches = HashMap::new();
+    let mut arches = IndexMap::new();
     arches.insert("rtl".into(), Arch::Code(spicemos));
 
     let vol = Rc::from(Entity {
         name: "voltage".into(),
         symbol: Symbol {},
-        generic: vec!["dc".into(), "tran".into()],
+        generic: vec![Generic::new("dc", "0"), Generic::new("tran", "")],
         port: vec!["p".into(), "n".into()],
         archs: arches,
     });
@@ -122,7 +118,8 @@ fn circuit() -> String {
     // Inverter schematic
     let mut cir = Schematic {
         toplevel: false,
-        instances: HashMap::new(),
+        level: None,
+        instances: IndexMap::new(),
     };
     cir.instances.insert(
             "pmos".into(),
@@ -139,7 +136,7 @@ fn circuit() -> String {
                 },
                 x: 0,
                 y: 0,
-                entity: pmos.clone(),
+                entity: pmos.clone().into(),
             });
     cir.instances.insert(
             "nmos".into(),
@@ -156,7 +153,7 @@ fn circuit() -> String {
                 },
                 x: 0,
                 y: 0,
-                entity: nmos.clone(),
+                entity: nmos.clone().into(),
             });
     // Inverter entity
     let inv = Rc::from(Entity {
@@ -170,12 +167,13 @@ fn circuit() -> String {
     // Buffer schematic
     let mut cir = Schematic {
         toplevel: false,
-        instances: HashMap::new(),
+        level: None,
+        instances: IndexMap::new(),
     };
     cir.instances.insert(
             "inv1".into(),
             Instance {
-                genericmap: HashMap::new(),
+                genericmap: IndexMap::new(),
                 portmap: collection!{
                     "in".into() => "in".into(),
                     "out".into() => "mid".into(),
@@ -184,12 +182,12 @@ fn circuit() -> String {
                 },
                 x: 0,
                 y: 0,
-                entity: inv.clone(),
+                entity: inv.clone().into(),
             });
     cir.instances.insert(
             "inv2".into(),
             Instance {
-                genericmap: HashMap::new(),
+                genericmap: IndexMap::new(),
                 portmap: collection!{
                     "in".into() => "mid".into(),
                     "out".into() => "out".into(),
@@ -198,7 +196,7 @@ fn circuit() -> String {
                 },
                 x: 0,
                 y: 0,
-                entity: inv.clone(),
+                entity: inv.clone().into(),
             });
 
     // Buffer entity
@@ -213,12 +211,13 @@ fn circuit() -> String {
     // Testbench schematic
     let mut cir = Schematic {
         toplevel: true,
-        instances: HashMap::new(),
+        level: None,
+        instances: IndexMap::new(),
     };
     cir.instances.insert(
             "buf".into(),
             Instance {
-                genericmap: HashMap::new(),
+                genericmap: IndexMap::new(),
                 portmap: collection!{
                     "in".into() => "in".into(),
                     "out".into() => "out".into(),
@@ -227,7 +226,7 @@ fn circuit() -> String {
                 },
                 x: 0,
                 y: 0,
-                entity: buf.clone(),
+                entity: buf.clone().into(),
             });
     cir.instances.insert(
             "input".into(),
@@ -242,7 +241,7 @@ fn circuit() -> String {
                 },
                 x: 0,
                 y: 0,
-                entity: vol.clone(),
+                entity: vol.clone().into(),
             });
 
     cir.instances.insert(
@@ -257,7 +256,7 @@ fn circuit() -> String {
                 },
                 x: 0,
                 y: 0,
-                entity: vol.clone(),
+                entity: vol.clone().into(),
             });
 
     // Testbench entity
@@ -270,18 +269,12 @@ fn circuit() -> String {
     });
 
     let conf = Configuration {
-        sim: Ngspice,
-        ent: tb,
         arch: Some("default".into()),
-        for_inst: RefCell::from(HashMap::new()),
-        all: HashMap::new(),
+        ..Configuration::new(Ngspice, tb.into())
     };
-    if let Definition::Code(code) = &conf.definition().unwrap()[0] {
-        println!("{}", code);
-        return code.into();
-    } else {
-        return "".into()
-    }
+    let bundle = conf.bundle("rc.sp").unwrap();
+    println!("{}", String::from_utf8_lossy(&bundle.files[0].contents));
+    bundle
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -303,60 +296,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let stream = tokio::net::TcpStream::connect(&addr).await?;
         stream.set_nodelay(true)?;
         let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
-        let rpc_network = Box::new(twoparty::VatNetwork::new(
-            reader,
-            writer,
-            rpc_twoparty_capnp::Side::Client,
-            Default::default(),
-        ));
-        let mut rpc_system = RpcSystem::new(rpc_network, None);
-        let sim: Simulator_capnp::simulator::Client<Simulator_capnp::tran::Owned> =
-            rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
-
-        tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
-
-        let mut request = sim.load_files_request();
-        let mut file = request.get().init_files(1).get(0);
-        file.set_name("rc.sp");
-        file.set_contents(cir.as_bytes());
-
-        let reply = request.send().promise.await.unwrap();
-
-        let cmd = reply.get().unwrap().get_commands().unwrap();
-        let mut request = cmd.tran_request();
-        let mut param = request.get();
-        param.set_step(1e-6);
-        param.set_start(0.0);
-        param.set_stop(2e-3);
-
-        let reply = request.send().promise.await.unwrap();
-
-        let res = reply.get().unwrap().get_result().unwrap();
-
-        let mut resdict: HashMap<String, Vec<f64>> = HashMap::new();
+        let (sim, rpc) = Server::connect(reader, writer);
+        tokio::task::spawn_local(rpc);
 
-        loop {
-            let reply = res.read_request().send().promise.await.unwrap();
-            let reply_data = reply.get().unwrap();
-            let data = reply_data.get_data().unwrap();
-            let more = reply_data.get_more();
-            for vec in data {
-                let name = vec.get_name().unwrap();
-                let data = vec.get_data();
-                // println!("{}", name);
-                match data.which().unwrap() {
-                    Simulator_capnp::vector::data::Real(data) => for item in data.unwrap() {
-                        resdict.entry(name.into()).or_insert(Vec::new()).push(item);
-                    },
-                    _ => println!("other data")
-                }
-            }
-            if !more {
-                break;
-            }
-        }
+        let commands = sim.load(&cir).await?;
+        let data = commands.tran(1e-6, 2e-3, 0.0, &[]).await?.dataset().await?;
 
-        plot(resdict)?;
+        plot(data)?;
 
         Ok(())
     }).await
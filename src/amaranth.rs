@@ -0,0 +1,215 @@
+//! An Amaranth (formerly nMigen) backend, to hand digital hierarchies to Python.
+//!
+//! Schematics become `Elaboratable` classes that take their generics as keyword arguments
+//! and expose their ports as signals. Leaves are `amaranth` dialect classes
+//! with the same interface, which are instantiated as submodules.
+
+use crate::*;
+
+#[derive(Copy, Clone, Default)]
+pub struct Amaranth;
+
+impl Simulator for Amaranth {
+    fn name(&self) -> &str {
+        "amaranth"
+    }
+    fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch> {
+        arch.dialects.get("amaranth")
+    }
+    fn synthesize_definition<S: Simulator>(&self, conf: &Configuration<S>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+        definition(conf, ckt)
+    }
+    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        let portmap = portmap.iter().map(|(port, net)| (port.clone(), signal_expr(None, net))).collect();
+        instantiation(conf.ent.get()?, name, genericmap, &portmap)
+    }
+}
+
+impl Amaranth {
+    /// A Python module with the classes of a configuration, the entity last, as the entry.
+    /// Library definitions are Python files that are bundled and imported from.
+    pub fn bundle<S: Simulator>(conf: &Configuration<S>) -> Result<Bundle, CodeError> {
+        let mut bundle = Bundle::new(&format!("{}.py", conf.ent.name()));
+        let mut imports = String::from("from amaranth.hdl import *\n");
+        let mut code = String::new();
+        for def in conf.definition()? {
+            match def {
+                Definition::Code(def) => {
+                    code.push_str("\n\n");
+                    code.push_str(def.trim_end());
+                    code.push('\n');
+                }
+                Definition::Library(lib) => {
+                    let name = bundle.add_library(&lib)?;
+                    imports.push_str(&format!("from {} import *\n", name.trim_end_matches(".py")));
+                }
+                Definition::Primitive => (),
+            }
+        }
+        bundle.add_entry(imports + &code, IndexSet::new())?;
+        Ok(bundle)
+    }
+}
+
+fn definition<S: Simulator>(conf: &Configuration<S>, sch: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+    let mut defs = conf.instance_definitions(sch)?;
+    let ent = conf.ent.get()?;
+    let mut res = format!("class {}(Elaboratable):\n", ent.name);
+    // keyword only, so generics without default can come in any order
+    let mut args = vec!["self".to_string()];
+    if !ent.generic.is_empty() {
+        args.push("*".into());
+    }
+    for g in &ent.generic {
+        match &g.default {
+            Some(default) => args.push(format!("{}={}", g.name, value(ent, g, default)?)),
+            None => args.push(g.name.clone()),
+        }
+    }
+    res.push_str(&format!("    def __init__({}):\n", args.join(", ")));
+    for g in &ent.generic {
+        res.push_str(&format!("        self.{} = {}\n", g.name, g.name));
+    }
+    for p in &ent.port {
+        let width = p.range.as_ref().map(|r| match r.indices(&IndexMap::new()) {
+            Ok(indices) => indices.len().to_string(),
+            // sized by the generics
+            Err(_) => format!("abs(({}) - ({})) + 1", r.left, r.right),
+        });
+        res.push_str(&format!("        self.{} = {}\n", p.name, signal(p, width)?));
+    }
+    if ent.generic.is_empty() && ent.port.is_empty() {
+        res.push_str("        pass\n");
+    }
+    res.push_str("\n    def elaborate(self, platform):\n");
+    // {expr} values can refer to the generics by name
+    for g in &ent.generic {
+        res.push_str(&format!("        {} = self.{}\n", g.name, g.name));
+    }
+    res.push_str("        m = Module()\n");
+    for (net, Net {port, range}) in conf.internal_nets(sch)? {
+        let width = range.map(|(left, right)| ((left - right).abs() + 1).to_string());
+        res.push_str(&format!("        {} = {}\n", net, signal(port, width)?));
+    }
+    for (name, inst) in &sch.instances {
+        // the bound entity is instantiated directly, with ports of this entity on self
+        let subconf = conf.get_conf(name, inst);
+        res.push_str(&subconf.located(|| {
+            let genericmap = subconf.instance_generics(inst)?;
+            let portmap = subconf.instance_ports(inst)?.iter()
+                .map(|(port, net)| (port.clone(), signal_expr(Some(ent), net)))
+                .collect();
+            instantiation(subconf.ent.get()?, name, &genericmap, &portmap)
+        })?);
+    }
+    res.push_str("        return m\n");
+    defs.insert(Definition::Code(res));
+    Ok(defs)
+}
+
+/// The signal constructor of a port, with the width of a bus
+fn signal(port: &Port, width: Option<String>) -> Result<String, CodeError> {
+    match (&port.nature, width) {
+        (Nature::Logic, Some(width)) => Ok(format!("Signal({})", width)),
+        (Nature::Logic, None) => Ok("Signal()".into()),
+        (Nature::Custom(shape), None) => Ok(format!("Signal({})", shape)),
+        _ => Err(ErrorKind::Unsupported(format!("{:?} port {} can not be simulated in Amaranth", port.nature, port.name)).into()),
+    }
+}
+
+/// A net as a Python expression, with the ports of the entity on `self`.
+/// A list of nets is given most significant bit first, but `Cat` takes the least significant first.
+fn signal_expr(ent: Option<&Entity>, net: &str) -> String {
+    if is_concat(net) {
+        let bits: Vec<String> = net.split_whitespace().rev().map(|bit| signal_expr(ent, bit)).collect();
+        return format!("Cat({})", bits.join(", "));
+    }
+    let base = net.split('[').next().unwrap_or(net);
+    match ent {
+        Some(ent) if ent.port.iter().any(|p| p.name == base) => format!("self.{}", net),
+        _ => net.into(),
+    }
+}
+
+/// A keyword argument value for the constructor of an Elaboratable
+fn value(ent: &Entity, g: &Generic, val: &str) -> Result<String, CodeError> {
+    Ok(match literal(ent, g, val)? {
+        Literal::Expr(val) | Literal::Other(val) => val.into(),
+        Literal::Real(num) => format!("{:?}", num),
+        Literal::String(val) => format!("\"{}\"", val),
+    })
+}
+
+/// A submodule with its generics as keyword arguments, and its ports driven or read
+/// according to their direction. The nets are Python expressions.
+fn instantiation(ent: &Entity, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+    if let Some(g) = genericmap.keys().find(|g| !ent.generic.iter().any(|eg| &eg.name == *g)) {
+        return Err(ErrorKind::UnknownGeneric {entity: ent.name.clone(), generic: g.clone()}.into());
+    }
+    let args = ent.generic.iter()
+        .filter_map(|g| genericmap.get(&g.name).map(|val| Ok(format!("{}={}", g.name, value(ent, g, val)?))))
+        .collect::<Result<Vec<String>, CodeError>>()?;
+    let mut res = format!("        m.submodules.{} = {} = {}({})\n", name, name, ent.name, args.join(", "));
+    for p in &ent.port {
+        let net = portmap.get(&p.name).ok_or_else(|| ErrorKind::MissingPort {entity: ent.name.clone(), port: p.name.clone()})?;
+        match p.direction {
+            Direction::In => res.push_str(&format!("        m.d.comb += {}.{}.eq({})\n", name, p.name, net)),
+            Direction::Out => res.push_str(&format!("        m.d.comb += {}.eq({}.{})\n", net, name, p.name)),
+            Direction::InOut => return Err(ErrorKind::Unsupported(format!("inout port {} in {} can not be connected in Amaranth", p.name, name)).into()),
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{code, repeater_tb};
+
+    #[test]
+    fn buffer() {
+        let inv = "class inv(Elaboratable):\n    def __init__(self, *, delay=1e-9):\n        self.a = Signal()\n        self.y = Signal()\n\n    def elaborate(self, platform):\n        m = Module()\n        m.d.comb += self.y.eq(~self.a)\n        return m\n";
+        let n = Generic {name: "n".into(), kind: GenericKind::Integer, default: Some("2".into()), ..Generic::default()};
+        let a = Port {range: Some(BusRange::new("n-1", RangeDirection::Downto, "0")), ..Port::new("a", Direction::In, Nature::Logic)};
+        let (_, tb) = repeater_tb(code("amaranth", Definition::Code(inv.into()), ""), vec![n], a, "a[0]", "{n*1e-9}", "x1 x0");
+        let conf = Configuration::new(Amaranth, tb.into());
+        let repeater = "class repeater(Elaboratable):\n\
+            \x20   def __init__(self, *, n=2):\n\
+            \x20       self.n = n\n\
+            \x20       self.a = Signal(abs((n-1) - (0)) + 1)\n\
+            \x20       self.y = Signal()\n\n\
+            \x20   def elaborate(self, platform):\n\
+            \x20       n = self.n\n\
+            \x20       m = Module()\n\
+            \x20       mid = Signal()\n\
+            \x20       m.submodules.inv1 = inv1 = inv(delay=n*1e-9)\n\
+            \x20       m.d.comb += inv1.a.eq(self.a[0])\n\
+            \x20       m.d.comb += mid.eq(inv1.y)\n\
+            \x20       m.submodules.inv2 = inv2 = inv()\n\
+            \x20       m.d.comb += inv2.a.eq(mid)\n\
+            \x20       m.d.comb += self.y.eq(inv2.y)\n\
+            \x20       return m\n";
+        let tb = "class tb(Elaboratable):\n\
+            \x20   def __init__(self):\n\
+            \x20       pass\n\n\
+            \x20   def elaborate(self, platform):\n\
+            \x20       m = Module()\n\
+            \x20       x1 = Signal()\n\
+            \x20       x0 = Signal()\n\
+            \x20       z = Signal()\n\
+            \x20       m.submodules.b1 = b1 = repeater()\n\
+            \x20       m.d.comb += b1.a.eq(Cat(x0, x1))\n\
+            \x20       m.d.comb += z.eq(b1.y)\n\
+            \x20       return m\n";
+        assert_eq!(conf.definition().unwrap(), indexset!{
+            Definition::Code(inv.into()),
+            Definition::Code(repeater.into()),
+            Definition::Code(tb.into()),
+        });
+        let bundle = Amaranth::bundle(&conf).unwrap();
+        let module = &bundle.files[0];
+        assert_eq!(module.name, "tb.py");
+        assert!(module.contents.starts_with(b"from amaranth.hdl import *\n\n\nclass inv(Elaboratable):\n"));
+        assert!(module.contents.ends_with(tb.as_bytes()));
+    }
+}
@@ -0,0 +1,126 @@
+//! A CXXRTL backend, to simulate digital hierarchies as C++ generated by Yosys.
+//!
+//! The design is emitted as Verilog like for Verilator,
+//! and bundled with a CMake project and a C++ harness that clocks the toplevel
+//! and streams all signals, which is what the SimServer `Cxxrtl` interface builds and runs.
+
+use crate::*;
+use crate::verilog::{definition, instantiation, sources};
+
+#[derive(Copy, Clone, Default)]
+pub struct Cxxrtl;
+
+impl Simulator for Cxxrtl {
+    fn name(&self) -> &str {
+        "cxxrtl"
+    }
+    fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch> {
+        arch.dialects.get("cxxrtl").or_else(|| arch.dialects.get("verilog"))
+    }
+    fn synthesize_definition<S: Simulator>(&self, conf: &Configuration<S>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+        definition(conf, ckt)
+    }
+    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        instantiation(conf.ent.get()?, name, genericmap, portmap)
+    }
+}
+
+impl Cxxrtl {
+    /// The Verilog of a toplevel entity, a CMake project that compiles it with Yosys,
+    /// and a `main.cpp` that toggles the `clock` input for the given number of cycles.
+    /// The files are ready to pass to `loadFiles` of a simulator server.
+    pub fn bundle<S: Simulator>(conf: &Configuration<S>, clock: &str, cycles: u64) -> Result<Bundle, CodeError> {
+        let ent = conf.ent.get()?;
+        match ent.port.iter().find(|p| p.name == clock) {
+            Some(p) if p.direction == Direction::In && p.range.is_none() => (),
+            _ => return Err(ErrorKind::MissingPort {entity: ent.name.clone(), port: clock.into()}.into()),
+        }
+        let mut bundle = Bundle::new("CMakeLists.txt");
+        let (code, libs) = sources(conf)?;
+        bundle.add(&format!("{}.v", ent.name), code);
+        let mut sources = vec![format!("${{CMAKE_SOURCE_DIR}}/{}.v", ent.name)];
+        for lib in libs {
+            sources.push(format!("${{CMAKE_SOURCE_DIR}}/{}", bundle.add_library(&lib)?));
+        }
+        let cmake = format!("cmake_minimum_required(VERSION 3.9)\n\n\
+            project (testbench)\n\n\
+            execute_process(COMMAND yosys-config --datdir OUTPUT_VARIABLE YOSYS_DATADIR OUTPUT_STRIP_TRAILING_WHITESPACE)\n\n\
+            include_directories(${{YOSYS_DATADIR}}/include ${{CMAKE_BINARY_DIR}})\n\n\
+            add_custom_command(OUTPUT {name}.hpp COMMAND yosys -p \"hierarchy -top {name}; write_cxxrtl {name}.hpp\" {sources} DEPENDS {name}.v)\n\n\
+            add_library(testbench SHARED main.cpp {name}.hpp)\n\
+            add_executable(standalone main.cpp {name}.hpp)\n",
+            name = ent.name, sources = sources.join(" "));
+        let main = format!("#include <cxxrtl_stream.hpp>\n\n\
+            #include \"{name}.hpp\"\n\n\
+            int main()\n\
+            {{\n\
+            \x20   cxxrtl_design::{top} top;\n\n\
+            \x20   cxxrtl::debug_items all_debug_items;\n\
+            \x20   top.debug_info(all_debug_items);\n\n\
+            \x20   cxxrtl::stream_writer stream;\n\
+            \x20   stream.timescale(1, \"us\");\n\
+            \x20   stream.add_without_memories(all_debug_items);\n\n\
+            \x20   top.step();\n\
+            \x20   stream.sample(0);\n\n\
+            \x20   for (int steps = 0; steps < {cycles}; ++steps) {{\n\
+            \x20       top.{clock}.set<bool>(false);\n\
+            \x20       top.step();\n\
+            \x20       stream.sample(steps*2 + 0);\n\n\
+            \x20       top.{clock}.set<bool>(true);\n\
+            \x20       top.step();\n\
+            \x20       stream.sample(steps*2 + 1);\n\
+            \x20   }}\n\
+            }}\n",
+            name = ent.name, top = mangle(&ent.name), clock = mangle(clock), cycles = cycles);
+        bundle.add("CMakeLists.txt", cmake);
+        bundle.add("main.cpp", main);
+        Ok(bundle)
+    }
+}
+
+/// The C++ name CXXRTL gives a module or port.
+/// Underscores are doubled and other characters escaped by their hex code.
+fn mangle(name: &str) -> String {
+    let mut res = String::from("p_");
+    for c in name.chars() {
+        match c {
+            '_' => res.push_str("__"),
+            c if c.is_ascii_alphanumeric() => res.push(c),
+            c => res.push_str(&format!("_{:x}_", c as u32)),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{code, entity, instance, schematic};
+
+    #[test]
+    fn blink() {
+        let mut lib = Library::new();
+        let counter = code("verilog", Definition::Code("module counter(input clk, output [11:0] q);\n  reg [11:0] q = 12'h0;\n  always @(posedge clk) q <= q + 1'b1;\nendmodule\n".into()), "");
+        lib.insert(entity("counter", Vec::new(), vec![
+            Port::new("clk", Direction::In, Nature::Logic),
+            Port {range: Some(BusRange::new("11", RangeDirection::Downto, "0")), ..Port::new("q", Direction::Out, Nature::Logic)},
+        ], collection!{"rtl".into() => counter})).unwrap();
+        let blink = lib.insert(entity("blink", Vec::new(), vec![Port::new("clk", Direction::In, Nature::Logic), Port::new("led", Direction::Out, Nature::Logic)], collection!{"structural".into() => schematic(true, collection!{
+            "c1".into() => instance("counter", IndexMap::new(), collection!{"clk".into() => "clk".into(), "q".into() => "count".into()}),
+        })})).unwrap();
+        let conf = Configuration::new(Cxxrtl, blink.into());
+        let bundle = Cxxrtl::bundle(&conf, "clk", 1000).unwrap();
+        let files = &bundle.files;
+        let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["blink.v", "CMakeLists.txt", "main.cpp"]);
+        let text: Vec<_> = files.iter().map(|f| String::from_utf8_lossy(&f.contents)).collect();
+        assert!(text[0].starts_with("module counter("));
+        assert!(text[0].ends_with("module blink (\n  input wire clk,\n  output wire led\n);\n  wire [11:0] count;\n  counter c1 (.clk(clk), .q(count));\nendmodule\n"));
+        assert!(text[1].contains("yosys -p \"hierarchy -top blink; write_cxxrtl blink.hpp\" ${CMAKE_SOURCE_DIR}/blink.v DEPENDS blink.v"));
+        assert!(text[2].contains("cxxrtl_design::p_blink top;"));
+        assert!(text[2].contains("steps < 1000;"));
+        assert!(text[2].contains("top.p_clk.set<bool>(true);"));
+        assert!(Cxxrtl::bundle(&conf, "led", 1000).is_err());
+        assert_eq!(mangle("led_r"), "p_led__r");
+    }
+}
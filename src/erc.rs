@@ -0,0 +1,149 @@
+//! Electrical rule checks of schematics, to find wiring mistakes before netlisting.
+//!
+//! Netlists are generated from whatever the portmaps say,
+//! so a typo in a net or port name just produces a floating node.
+
+use indexmap::{IndexMap, IndexSet};
+use crate::*;
+
+/// The node SPICE simulators use as ground reference
+const GROUND: &str = "0";
+
+/// Check a schematic of an entity. Buses are expanded to their scalar nodes.
+pub fn check(ent: &Entity, sch: &Schematic) -> Vec<(Severity, ErrorKind)> {
+    let mut found = Vec::new();
+    // what connects to each node, as instance/port, and the direction seen from the node
+    let mut nodes: IndexMap<String, Vec<(String, Direction)>> = IndexMap::new();
+    // inside the schematic, an input of the entity drives its net
+    let defaults = ent.generic_defaults();
+    for p in &ent.port {
+        let direction = match p.direction {
+            Direction::In => Direction::Out,
+            Direction::Out => Direction::In,
+            Direction::InOut => Direction::InOut,
+        };
+        for node in p.nodes(&p.name, &defaults).unwrap_or_else(|_| vec![p.name.clone()]) {
+            nodes.entry(node).or_default().push((p.name.clone(), direction));
+        }
+    }
+    let mut electrical = false;
+    for (name, inst) in &sch.instances {
+        let child = match inst.entity.get() {
+            Ok(child) => child,
+            Err(_) => continue,
+        };
+        for port in inst.portmap.keys().filter(|port| !child.port.iter().any(|p| &p.name == *port)) {
+            found.push((Severity::Error, ErrorKind::UnknownPort {entity: child.name.clone(), port: format!("{}/{}", name, port)}));
+        }
+        let generics = child.generic_values(&inst.genericmap).unwrap_or_else(|_| child.generic_defaults());
+        for p in &child.port {
+            // ports that are not connected at all are an error of netlisting
+            let net = match inst.portmap.get(&p.name) {
+                Some(net) => net,
+                None => continue,
+            };
+            electrical |= p.nature == Nature::Electrical;
+            for node in p.nodes(net, &generics).unwrap_or_else(|_| vec![net.clone()]) {
+                nodes.entry(node).or_default().push((format!("{}/{}", name, p.name), p.direction));
+            }
+        }
+    }
+    let mut unused = IndexSet::new();
+    for p in &ent.port {
+        let pnodes = p.nodes(&p.name, &defaults).unwrap_or_else(|_| vec![p.name.clone()]);
+        if pnodes.iter().all(|node| nodes[node].len() == 1) {
+            found.push((Severity::Warning, ErrorKind::UnusedPort {entity: ent.name.clone(), port: p.name.clone()}));
+            unused.extend(pnodes);
+        }
+    }
+    for (node, conns) in &nodes {
+        if conns.len() == 1 && node != GROUND && !unused.contains(node) {
+            found.push((Severity::Warning, ErrorKind::SingleConnection {net: node.clone(), port: conns[0].0.clone()}));
+        }
+        let drivers: Vec<String> = conns.iter()
+            .filter(|(_, direction)| *direction == Direction::Out)
+            .map(|(port, _)| port.clone())
+            .collect();
+        if drivers.len() > 1 {
+            found.push((Severity::Error, ErrorKind::Short {net: node.clone(), drivers}));
+        }
+    }
+    // subcircuits get their reference through their ports
+    if sch.toplevel && electrical && !nodes.contains_key(GROUND) {
+        found.push((Severity::Error, ErrorKind::NoGround));
+    }
+    found
+}
+
+impl<S: Simulator> Configuration<S> {
+    /// Check the schematics in the tree below this configuration.
+    /// A schematic is checked once, at the first instance that uses it.
+    /// Architectures that can not be selected are left to `diagnose`.
+    pub fn erc(&self, diagnostics: &mut Vec<Diagnostic>) {
+        self.erc_tree(diagnostics, &mut IndexSet::new());
+    }
+
+    fn erc_tree(&self, diagnostics: &mut Vec<Diagnostic>, checked: &mut IndexSet<(String, String)>) {
+        let (arch, sch) = match self.get_named_arch() {
+            Ok((arch, Arch::Schematic(sch))) => (arch, sch),
+            _ => return,
+        };
+        if !checked.insert((self.ent.name().into(), arch.clone())) {
+            return;
+        }
+        if let Ok(ent) = self.ent.get() {
+            for (severity, kind) in check(ent, sch) {
+                let error = CodeError::from(kind).within(|| self.context());
+                diagnostics.push(Diagnostic {severity, error});
+            }
+        }
+        for (name, inst) in &sch.instances {
+            self.get_conf(name, inst).erc_tree(diagnostics, checked);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{entity, instance, inverter_tb, schematic};
+
+    #[test]
+    fn wiring_mistakes() {
+        let mut lib: Library = toml::from_str(&std::fs::read_to_string("data/inverter.toml").unwrap()).unwrap();
+        lib.insert(entity("buf", Vec::new(), vec![Port::new("a", Direction::In, Nature::Logic), Port::new("y", Direction::Out, Nature::Logic)], collection!{"rtl".into() => Arch::Code(CodeDialectArch::new())})).unwrap();
+        let inst = |entity: &str, portmap| instance(entity, IndexMap::new(), portmap);
+        let top = lib.insert(entity("top", Vec::new(), vec![Port::new("en", Direction::In, Nature::Logic), Port::new("q", Direction::Out, Nature::Logic)], collection!{"default".into() => schematic(true, collection!{
+            "b1".into() => inst("buf", collection!{"a".into() => "d".into(), "y".into() => "q".into()}),
+            "b2".into() => inst("buf", collection!{"a".into() => "d".into(), "y".into() => "q".into()}),
+            "inv1".into() => inst("inverter", collection!{
+                "vdd".into() => "vdd".into(),
+                "gnd".into() => "gnd".into(),
+                "in".into() => "vdd".into(),
+                "out".into() => "q".into(),
+                "ou".into() => "mid".into(),
+            }),
+        })})).unwrap();
+        let sch = match &top.archs["default"] {
+            Arch::Schematic(sch) => sch,
+            _ => unreachable!(),
+        };
+        let found: Vec<String> = check(&top, sch).iter().map(|(_, kind)| kind.to_string()).collect();
+        assert_eq!(found, vec![
+            "no port inv1/ou on inverter",
+            "port en of top is not used",
+            "net q is driven by outputs b1/y, b2/y, inv1/out",
+            "net gnd only connects to inv1/gnd",
+            "no ground reference, net 0 is not connected",
+        ]);
+
+        let mut diagnostics = Vec::new();
+        Configuration::new(Ngspice, inverter_tb().into()).erc(&mut diagnostics);
+        let found: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        // the testbench has no sources, and the inverter is checked once
+        assert_eq!(found, vec![
+            "warning: tb tb(default) for ngspice: net in only connects to inv1/in",
+            "warning: tb tb(default) for ngspice: net out only connects to inv2/out",
+        ]);
+    }
+}
@@ -0,0 +1,179 @@
+//! Errors of loading and synthesizing designs.
+//!
+//! An error has a kind, and the place in the hierarchy where it occurred
+//! once it passes through the configuration of an instance.
+//! Synthesis stops at the first error, while `Configuration::diagnose`
+//! collects all of them as diagnostics, together with warnings.
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// What went wrong
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// No architecture of the entity, or not the chosen one, has a dialect for the simulator
+    NoDialect { entity: String, arch: Option<String>, simulator: String },
+    /// The chosen architecture does not exist
+    NoArch { entity: String, arch: String },
+    /// A reference to an entity that is not resolved against a library
+    Unresolved(String),
+    /// A reference to an entity that is not in the library
+    NoEntity(String),
+    /// Entities that instantiate each other or entities that are not in the library
+    Recursive(Vec<String>),
+    /// An instance does not connect a port of its entity
+    MissingPort { entity: String, port: String },
+    /// An instance connects a port its entity does not have
+    UnknownPort { entity: String, port: String },
+    /// A port of the entity that nothing inside its schematic connects to
+    UnusedPort { entity: String, port: String },
+    /// A net with a single connection, given as instance/port
+    SingleConnection { net: String, port: String },
+    /// A net driven by more than one output
+    Short { net: String, drivers: Vec<String> },
+    /// A toplevel schematic with electrical nets but no ground
+    NoGround,
+    /// A reference template that uses a port or generic its entity does not have
+    UnknownReference { entity: String, dialect: String, reference: String },
+    /// A port of the entity that a reference template does not use
+    UnusedTemplatePort { entity: String, dialect: String, port: String },
+    /// An instance has no value for a generic without default
+    MissingGeneric { entity: String, generic: String },
+    /// An instance sets a generic its entity does not have
+    UnknownGeneric { entity: String, generic: String },
+    /// A generic value of the wrong kind or out of range
+    InvalidGeneric { entity: String, message: String },
+    /// A bus range that can not be evaluated, or nets that do not match its width
+    Bus(String),
+    /// Something the simulator or language can not express
+    Unsupported(String),
+    Template(Box<handlebars::TemplateRenderError>),
+    Io { path: PathBuf, error: std::io::Error },
+    /// Invalid source code, or any other error
+    Compile(String),
+}
+
+/// The place in the hierarchy of an error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Context {
+    /// The configured entity and the instance names down from it
+    pub path: Vec<String>,
+    pub entity: String,
+    pub arch: Option<String>,
+    pub simulator: String,
+}
+
+#[derive(Debug)]
+pub struct CodeError {
+    pub kind: ErrorKind,
+    /// Where the error occurred, if it happened inside a configuration
+    pub context: Option<Box<Context>>,
+}
+
+impl CodeError {
+    /// Locate the error, unless a deeper configuration already did
+    pub fn within(mut self, context: impl FnOnce() -> Context) -> Self {
+        if self.context.is_none() {
+            self.context = Some(Box::new(context()));
+        }
+        self
+    }
+
+    /// The instance path, like `tb/buf/inv1`, or empty if unknown
+    pub fn path(&self) -> String {
+        self.context.as_ref().map_or(String::new(), |ctx| ctx.path.join("/"))
+    }
+}
+
+impl From<ErrorKind> for CodeError {
+    fn from(kind: ErrorKind) -> Self {
+        CodeError {kind, context: None}
+    }
+}
+
+impl From<handlebars::TemplateRenderError> for CodeError {
+    fn from(error: handlebars::TemplateRenderError) -> Self {
+        ErrorKind::Template(Box::new(error)).into()
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::NoDialect {entity, arch: Some(arch), simulator} => write!(f, "architecture {} of {} has no dialect for {}", arch, entity, simulator),
+            ErrorKind::NoDialect {entity, arch: None, simulator} => write!(f, "no architecture of {} for {}", entity, simulator),
+            ErrorKind::NoArch {entity, arch} => write!(f, "no architecture {} of {}", arch, entity),
+            ErrorKind::Unresolved(entity) => write!(f, "unresolved entity {}", entity),
+            ErrorKind::NoEntity(entity) => write!(f, "no entity {} in library", entity),
+            ErrorKind::Recursive(entities) => write!(f, "unresolved or recursive entities {}", entities.join(", ")),
+            ErrorKind::MissingPort {entity, port} => write!(f, "port {} of {} is not connected", port, entity),
+            ErrorKind::UnknownPort {entity, port} => write!(f, "no port {} on {}", port, entity),
+            ErrorKind::UnusedPort {entity, port} => write!(f, "port {} of {} is not used", port, entity),
+            ErrorKind::SingleConnection {net, port} => write!(f, "net {} only connects to {}", net, port),
+            ErrorKind::Short {net, drivers} => write!(f, "net {} is driven by outputs {}", net, drivers.join(", ")),
+            ErrorKind::NoGround => f.write_str("no ground reference, net 0 is not connected"),
+            ErrorKind::UnknownReference {entity, dialect, reference} => write!(f, "{} reference of {} uses unknown {}", dialect, entity, reference),
+            ErrorKind::UnusedTemplatePort {entity, dialect, port} => write!(f, "{} reference of {} does not use port {}", dialect, entity, port),
+            ErrorKind::MissingGeneric {entity, generic} => write!(f, "no value for generic {} of {}", generic, entity),
+            ErrorKind::UnknownGeneric {entity, generic} => write!(f, "no generic {} on {}", generic, entity),
+            ErrorKind::InvalidGeneric {entity, message} => write!(f, "{} on {}", message, entity),
+            ErrorKind::Bus(message) | ErrorKind::Unsupported(message) | ErrorKind::Compile(message) => f.write_str(message),
+            ErrorKind::Template(error) => write!(f, "template error: {}", error),
+            ErrorKind::Io {path, error} => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl fmt::Display for CodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.context {
+            Some(ctx) => {
+                let arch = ctx.arch.as_ref().map_or(String::new(), |arch| format!("({})", arch));
+                write!(f, "{} {}{} for {}: {}", ctx.path.join("/"), ctx.entity, arch, ctx.simulator, self.kind)
+            }
+            None => self.kind.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Template(error) => Some(error.as_ref()),
+            ErrorKind::Io {error, ..} => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// An error or warning found by checking a configuration
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub error: CodeError,
+}
+
+impl Diagnostic {
+    pub fn error(error: CodeError) -> Self {
+        Diagnostic {severity: Severity::Error, error}
+    }
+
+    pub fn warning(error: CodeError) -> Self {
+        Diagnostic {severity: Severity::Warning, error}
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.error),
+            Severity::Error => write!(f, "error: {}", self.error),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Evaluation of simple arithmetic on generics, such as the `N-1` in a bus range.
+
+use crate::units::parse_si;
+
+/// Evaluate an expression of numbers, variables, `+ - * /` and parentheses.
+/// Numbers may have SI scale suffixes. Variables are looked up with `var`.
+pub fn eval(expr: &str, var: &dyn Fn(&str) -> Option<f64>) -> Result<f64, String> {
+    let tokens = tokenize(expr)?;
+    let mut parser = Parser {tokens: &tokens, pos: 0, var};
+    let val = parser.sum()?;
+    if parser.pos != tokens.len() {
+        return Err(format!("unexpected {} in {}", tokens[parser.pos], expr));
+    }
+    Ok(val)
+}
+
+fn tokenize(expr: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if "+-*/()".contains(c) {
+            tokens.push(c.to_string());
+            chars.next();
+        } else if c.is_alphanumeric() || c == '_' || c == '.' {
+            let mut tok = String::new();
+            while let Some(&c) = chars.peek() {
+                // the sign of an exponent, as in 1e-3
+                let exponent = tok.starts_with(|c: char| c.is_ascii_digit()) && tok.ends_with(['e', 'E']) && "+-".contains(c);
+                if !(c.is_alphanumeric() || c == '_' || c == '.' || exponent) {
+                    break;
+                }
+                tok.push(c);
+                chars.next();
+            }
+            tokens.push(tok);
+        } else {
+            return Err(format!("unexpected {} in {}", c, expr));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+    var: &'a dyn Fn(&str) -> Option<f64>,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        let tok = self.tokens.get(self.pos)?;
+        self.pos += 1;
+        Some(tok)
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut val = self.product()?;
+        while let Some(op @ ("+" | "-")) = self.peek() {
+            self.pos += 1;
+            let rhs = self.product()?;
+            if op == "+" { val += rhs } else { val -= rhs }
+        }
+        Ok(val)
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut val = self.atom()?;
+        while let Some(op @ ("*" | "/")) = self.peek() {
+            self.pos += 1;
+            let rhs = self.atom()?;
+            if op == "*" { val *= rhs } else { val /= rhs }
+        }
+        Ok(val)
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        match self.next() {
+            Some("-") => Ok(-self.atom()?),
+            Some("(") => {
+                let val = self.sum()?;
+                match self.next() {
+                    Some(")") => Ok(val),
+                    _ => Err("missing )".into()),
+                }
+            }
+            Some(tok) if tok.starts_with(|c: char| c.is_ascii_digit() || c == '.') => {
+                parse_si(tok, None).ok_or_else(|| format!("invalid number {}", tok))
+            }
+            Some(tok) if tok != ")" && !"+*/".contains(tok) => {
+                (self.var)(tok).ok_or_else(|| format!("unknown value {}", tok))
+            }
+            Some(tok) => Err(format!("unexpected {}", tok)),
+            None => Err("unexpected end of expression".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let var = |name: &str| if name == "N" { Some(8.0) } else { None };
+        assert_eq!(eval("N-1", &var), Ok(7.0));
+        assert_eq!(eval("2*(N+1)/3", &var), Ok(6.0));
+        assert_eq!(eval("-1u*2", &var), Ok(-2e-6));
+        assert_eq!(eval("1e-3*1e+3-N", &var), Ok(-7.0));
+        assert!(eval("M", &var).is_err());
+        assert!(eval("(N", &var).is_err());
+        assert!(eval("N N", &var).is_err());
+    }
+}
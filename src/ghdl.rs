@@ -0,0 +1,263 @@
+//! A VHDL backend, to simulate digital hierarchies with GHDL.
+//!
+//! Schematics become structural architectures that instantiate components,
+//! and a toplevel schematic also gets a configuration unit that binds
+//! every instance to the entity and architecture chosen by the `Configuration`.
+
+use crate::*;
+
+#[derive(Copy, Clone, Default)]
+pub struct Ghdl;
+
+impl Simulator for Ghdl {
+    fn name(&self) -> &str {
+        "ghdl"
+    }
+    fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch> {
+        arch.dialects.get("ghdl").or_else(|| arch.dialects.get("vhdl"))
+    }
+    fn synthesize_definition<S: Simulator>(&self, conf: &Configuration<S>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+        definition(conf, ckt)
+    }
+    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        instantiation(conf.ent.get()?, name, genericmap, portmap)
+    }
+    fn synthesize_declaration<S: Simulator>(&self, conf: &Configuration<S>) -> Result<String, CodeError> {
+        component(conf.ent.get()?)
+    }
+}
+
+fn definition<S: Simulator>(conf: &Configuration<S>, sch: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+    let mut defs = conf.instance_definitions(sch)?;
+    let ent = conf.ent.get()?;
+    let (arch, _) = conf.get_named_arch()?;
+    let mut res = String::new();
+    res.push_str("library ieee;\nuse ieee.std_logic_1164.all;\n\n");
+    res.push_str(&format!("entity {} is\n", ent.name));
+    res.push_str(&interface(ent, "  ")?);
+    res.push_str(&format!("end entity {};\n\n", ent.name));
+    res.push_str(&format!("architecture {} of {} is\n", arch, ent.name));
+    let mut components = IndexSet::new();
+    for inst in sch.instances.values() {
+        if components.insert(inst.entity.name()) {
+            res.push_str(&component(inst.entity.get()?)?);
+        }
+    }
+    for (net, Net {port, range}) in conf.internal_nets(sch)? {
+        let range = match (range, &port.range) {
+            (Some((left, right)), Some(bus)) => Some(format!("{} {} {}", left, direction(&bus.direction), right)),
+            _ => None,
+        };
+        res.push_str(&format!("  signal {} : {};\n", net, port_type(port, range)?));
+    }
+    res.push_str("begin\n");
+    for (name, inst) in &sch.instances {
+        // the component is instantiated, the configuration binds it
+        res.push_str(&conf.get_conf(name, inst).located(|| instantiation(inst.entity.get()?, name, &inst.genericmap, &inst.portmap))?);
+    }
+    res.push_str(&format!("end architecture {};\n", arch));
+    if sch.toplevel {
+        res.push_str(&format!("\nconfiguration {}_cfg of {} is\n", ent.name, ent.name));
+        res.push_str(&block(conf, arch, sch, "  ")?);
+        res.push_str(&format!("end configuration {}_cfg;\n", ent.name));
+    }
+    defs.insert(Definition::Code(res));
+    Ok(defs)
+}
+
+/// The component declaration of an entity
+fn component(ent: &Entity) -> Result<String, CodeError> {
+    Ok(format!("  component {} is\n{}  end component;\n", ent.name, interface(ent, "    ")?))
+}
+
+/// The generic and port clauses of an entity or component
+fn interface(ent: &Entity, indent: &str) -> Result<String, CodeError> {
+    let mut res = String::new();
+    if !ent.generic.is_empty() {
+        let generics = ent.generic.iter().map(|g| {
+            let typ = match g.kind {
+                GenericKind::Integer => "integer",
+                GenericKind::Real | GenericKind::Quantity => "real",
+                GenericKind::String => "string",
+            };
+            Ok(match &g.default {
+                Some(default) => format!("{}  {} : {} := {}", indent, g.name, typ, value(ent, g, default)?),
+                None => format!("{}  {} : {}", indent, g.name, typ),
+            })
+        }).collect::<Result<Vec<String>, CodeError>>()?;
+        res.push_str(&format!("{}generic (\n{}\n{});\n", indent, generics.join(";\n"), indent));
+    }
+    if !ent.port.is_empty() {
+        let ports = ent.port.iter().map(|p| {
+            let range = p.range.as_ref().map(|r| format!("{} {} {}", r.left, direction(&r.direction), r.right));
+            let mode = match p.direction {
+                Direction::In => "in",
+                Direction::Out => "out",
+                Direction::InOut => "inout",
+            };
+            Ok(format!("{}  {} : {} {}", indent, p.name, mode, port_type(p, range)?))
+        }).collect::<Result<Vec<String>, CodeError>>()?;
+        res.push_str(&format!("{}port (\n{}\n{});\n", indent, ports.join(";\n"), indent));
+    }
+    Ok(res)
+}
+
+fn direction(direction: &RangeDirection) -> &'static str {
+    match direction {
+        RangeDirection::To => "to",
+        RangeDirection::Downto => "downto",
+    }
+}
+
+/// The VHDL type of a port, with the range of a bus
+fn port_type(port: &Port, range: Option<String>) -> Result<String, CodeError> {
+    let (scalar, vector) = match &port.nature {
+        Nature::Logic => ("std_logic".to_string(), "std_logic_vector".to_string()),
+        Nature::Custom(typ) => (typ.clone(), typ.clone()),
+        Nature::Electrical => return Err(ErrorKind::Unsupported(format!("electrical port {} can not be simulated in VHDL", port.name)).into()),
+    };
+    Ok(match range {
+        Some(range) => format!("{}({})", vector, range),
+        None => scalar,
+    })
+}
+
+/// A generic value as a VHDL expression, where a real is always written with a decimal point
+fn value(ent: &Entity, g: &Generic, val: &str) -> Result<String, CodeError> {
+    Ok(match literal(ent, g, val)? {
+        Literal::Expr(val) | Literal::Other(val) => val.into(),
+        Literal::Real(num) => {
+            // VHDL reals need a decimal point
+            let num = format!("{:?}", num);
+            match num.find('e') {
+                Some(e) if !num[..e].contains('.') => format!("{}.0{}", &num[..e], &num[e..]),
+                _ => num,
+            }
+        }
+        Literal::String(val) => format!("\"{}\"", val),
+    })
+}
+
+/// A component instantiation statement
+fn instantiation(ent: &Entity, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+    let mut res = format!("  {}: {}\n", name, ent.name);
+    let generics = ent.generic.iter()
+        .filter_map(|g| genericmap.get(&g.name).map(|val| Ok(format!("{} => {}", g.name, value(ent, g, val)?))))
+        .collect::<Result<Vec<String>, CodeError>>()?;
+    if !generics.is_empty() {
+        res.push_str(&format!("    generic map ({})\n", generics.join(", ")));
+    }
+    let values = ent.generic_values(genericmap)?;
+    let mut ports = Vec::new();
+    for p in &ent.port {
+        let net = portmap.get(&p.name).ok_or_else(|| ErrorKind::MissingPort {entity: ent.name.clone(), port: p.name.clone()})?;
+        match &p.range {
+            // VHDL has no concatenation of nets as an actual, so each bit is associated on its own
+            Some(range) if is_concat(net) => {
+                let indices = range.indices(&values).map_err(ErrorKind::Bus)?;
+                let nets = p.nodes(net, &values).map_err(ErrorKind::Bus)?;
+                ports.extend(indices.iter().zip(nets).map(|(i, net)| format!("{}({}) => {}", p.name, i, net)));
+            }
+            _ => ports.push(format!("{} => {}", p.name, net)),
+        }
+    }
+    if !ports.is_empty() {
+        res.push_str(&format!("    port map ({})\n", ports.join(", ")));
+    }
+    res.pop();
+    res.push_str(";\n");
+    Ok(res)
+}
+
+/// The block configuration of a schematic, binding every instance
+fn block<S: Simulator>(conf: &Configuration<S>, arch: &str, sch: &Schematic, indent: &str) -> Result<String, CodeError> {
+    let mut res = format!("{}for {}\n", indent, arch);
+    for (name, inst) in &sch.instances {
+        let subconf = conf.get_conf(name, inst);
+        let ent = subconf.ent.get()?;
+        let (subarch, arch) = subconf.located(|| {
+            subconf.check_binding(inst)?;
+            subconf.get_named_arch()
+        })?;
+        res.push_str(&format!("{}  for {}: {}\n", indent, name, inst.entity.name()));
+        res.push_str(&format!("{}    use entity work.{}({})", indent, ent.name, subarch));
+        // a substituted entity maps its generics and ports to those of the component
+        if !subconf.genericmap.is_empty() {
+            let generics = subconf.genericmap.iter().map(|(formal, actual)| {
+                let actual = match (quoted(actual), ent.generic.iter().find(|g| &g.name == formal)) {
+                    (Some(literal), Some(g)) => subconf.located(|| value(ent, g, literal))?,
+                    (Some(literal), None) => literal.into(),
+                    (None, _) => actual.clone(),
+                };
+                Ok(format!("{} => {}", formal, actual))
+            }).collect::<Result<Vec<String>, CodeError>>()?;
+            res.push_str(&format!("\n{}      generic map ({})", indent, generics.join(", ")));
+        }
+        if !subconf.portmap.is_empty() {
+            let ports: Vec<String> = subconf.portmap.iter().map(|(formal, actual)| format!("{} => {}", formal, quoted(actual).unwrap_or(actual))).collect();
+            res.push_str(&format!("\n{}      port map ({})", indent, ports.join(", ")));
+        }
+        res.push_str(";\n");
+        if let Arch::Schematic(subsch) = arch {
+            res.push_str(&block(&subconf, subarch, subsch, &format!("{}    ", indent))?);
+        }
+        res.push_str(&format!("{}  end for;\n", indent));
+    }
+    res.push_str(&format!("{}end for;\n", indent));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{code, repeater_tb};
+
+    #[test]
+    fn buffer() {
+        let inv = code("vhdl", Definition::Code("architecture rtl of inv is begin y <= not a; end rtl;".into()), "  {{name}}: inv port map (a => {{port.a}}, y => {{port.y}});\n");
+        let a = Port {range: Some(BusRange::new("1", RangeDirection::Downto, "0")), ..Port::new("a", Direction::In, Nature::Logic)};
+        let (_, tb) = repeater_tb(inv, Vec::new(), a, "a(0)", "2n", "x0 x1");
+        let conf = Configuration::new(Ghdl, tb.into());
+        let repeater = "library ieee;\nuse ieee.std_logic_1164.all;\n\n\
+            entity repeater is\n  port (\n    a : in std_logic_vector(1 downto 0);\n    y : out std_logic\n  );\nend entity repeater;\n\n\
+            architecture structural of repeater is\n\
+            \x20 component inv is\n    generic (\n      delay : real := 1.0e-9\n    );\n    port (\n      a : in std_logic;\n      y : out std_logic\n    );\n  end component;\n\
+            \x20 signal mid : std_logic;\n\
+            begin\n\
+            \x20 inv1: inv\n    generic map (delay => 2.0e-9)\n    port map (a => a(0), y => mid);\n\
+            \x20 inv2: inv\n    port map (a => mid, y => y);\n\
+            end architecture structural;\n";
+        let tb = "library ieee;\nuse ieee.std_logic_1164.all;\n\n\
+            entity tb is\nend entity tb;\n\n\
+            architecture test of tb is\n\
+            \x20 component repeater is\n    port (\n      a : in std_logic_vector(1 downto 0);\n      y : out std_logic\n    );\n  end component;\n\
+            \x20 signal x0 : std_logic;\n  signal x1 : std_logic;\n  signal z : std_logic;\n\
+            begin\n\
+            \x20 b1: repeater\n    port map (a(1) => x0, a(0) => x1, y => z);\n\
+            end architecture test;\n\n\
+            configuration tb_cfg of tb is\n\
+            \x20 for test\n\
+            \x20   for b1: repeater\n\
+            \x20     use entity work.repeater(structural);\n\
+            \x20     for structural\n\
+            \x20       for inv1: inv\n\
+            \x20         use entity work.inv(rtl);\n\
+            \x20       end for;\n\
+            \x20       for inv2: inv\n\
+            \x20         use entity work.inv(rtl);\n\
+            \x20       end for;\n\
+            \x20     end for;\n\
+            \x20   end for;\n\
+            \x20 end for;\n\
+            end configuration tb_cfg;\n";
+        let defs = conf.definition().unwrap();
+        assert_eq!(defs, indexset!{
+            Definition::Code("architecture rtl of inv is begin y <= not a; end rtl;".into()),
+            Definition::Code(repeater.into()),
+            Definition::Code(tb.into()),
+        });
+        // the output parses as VHDL
+        assert!(vhdl::parse_entities_str(repeater).is_ok());
+        assert!(vhdl::parse_entities_str(tb).is_ok());
+    }
+}
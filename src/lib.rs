@@ -1,103 +1,1015 @@
-use std::collections::HashMap;
 use std::rc::Rc;
 use std::cell::{Ref, RefCell};
-use std::path::PathBuf;
-use handlebars::Handlebars;
-use serde::Serialize;
-use indexmap::{indexset, IndexSet};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use indexmap::{indexset, IndexSet, IndexMap};
 
-/// Macro for HashMap literals
+pub mod units;
+pub mod error;
+pub mod erc;
+pub mod expr;
+pub mod template;
+pub mod vhdl;
+pub mod ghdl;
+pub mod verilog;
+pub mod cxxrtl;
+pub mod amaranth;
+pub mod registry;
+pub mod waveform;
+pub mod raw;
+#[cfg(feature = "simserver")]
+pub mod simserver;
+
+pub use error::{CodeError, Context, Diagnostic, ErrorKind, Severity};
+pub use ghdl::Ghdl;
+pub use verilog::Verilator;
+pub use cxxrtl::Cxxrtl;
+pub use amaranth::Amaranth;
+pub use registry::{Dynamic, Registry};
+
+/// Macro for map and set literals
 #[macro_export]
 macro_rules! collection {
     // map-like
     ($($k:expr => $v:expr),* $(,)?) => {
-        std::iter::Iterator::collect(std::array::IntoIter::new([$(($k, $v),)*]))
+        std::iter::Iterator::collect(std::iter::IntoIterator::into_iter([$(($k, $v),)*]))
     };
     // set-like
     ($($v:expr),* $(,)?) => {
-        std::iter::Iterator::collect(std::array::IntoIter::new([$($v,)*]))
+        std::iter::Iterator::collect(std::iter::IntoIterator::into_iter([$($v,)*]))
     };
 }
 
 
+#[derive(Serialize, Deserialize)]
 pub struct Entity {
     pub name: String,
+    #[serde(default)]
     pub symbol: Symbol,
-    pub generic: Vec<String>,
-    pub port: Vec<String>,
-    pub archs: HashMap<String, Arch>,
+    pub generic: Vec<Generic>,
+    pub port: Vec<Port>,
+    /// The architectures in order of preference.
+    /// Without configuration, the first one that supports the simulator is used.
+    pub archs: IndexMap<String, Arch>,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Arch {
     Schematic(Schematic),
     Code(CodeDialectArch),
     //TranspiledCode(???),
 }
 
-pub struct Symbol;
+impl Arch {
+    /// The abstraction level this architecture is tagged with
+    pub fn level(&self) -> Option<Level> {
+        match self {
+            Arch::Schematic(sch) => sch.level,
+            Arch::Code(code) => code.level,
+        }
+    }
+}
+
+/// The abstraction level of an architecture, from least to most detailed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Ideal,
+    Behavioral,
+    Transistor,
+    Extracted,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Symbol {}
+
+/// The type of value a generic accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GenericKind {
+    Integer,
+    Real,
+    /// Any text, passed to the simulator as is
+    #[default]
+    String,
+    /// A real number with optional SI scale and unit, like `1.5uF`
+    Quantity,
+}
+
+/// A generic parameter of an entity
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Generic {
+    pub name: String,
+    #[serde(default)]
+    pub kind: GenericKind,
+    /// The value used when an instance does not specify one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    /// The unit of a quantity, like `V` or `F`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// The allowed range of numeric values, inclusive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub doc: String,
+}
+
+impl Generic {
+    pub fn new(name: &str, default: &str) -> Generic {
+        Generic {name: name.into(), default: Some(default.into()), ..Generic::default()}
+    }
+
+    /// Check a value against the kind and range of this generic.
+    /// Braced `{expr}` values are evaluated by the simulator and always accepted.
+    pub fn check(&self, value: &str) -> Result<(), String> {
+        if value.starts_with('{') && value.ends_with('}') {
+            return Ok(());
+        }
+        let number = match self.kind {
+            GenericKind::String => return Ok(()),
+            GenericKind::Integer => value.trim().parse::<i64>().ok().map(|i| i as f64),
+            GenericKind::Real => value.trim().parse::<f64>().ok(),
+            GenericKind::Quantity => units::parse_si(value, self.unit.as_deref()),
+        };
+        let number = number.ok_or_else(|| format!("{} = {} is not a valid {:?}", self.name, value, self.kind))?;
+        if self.min.is_some_and(|min| number < min) || self.max.is_some_and(|max| number > max) {
+            return Err(format!("{} = {} is out of range [{}, {}]", self.name, value,
+                self.min.map_or("-inf".into(), |min| min.to_string()),
+                self.max.map_or("inf".into(), |max| max.to_string())));
+        }
+        Ok(())
+    }
+}
+
+impl From<&str> for Generic {
+    fn from(name: &str) -> Self {
+        Generic {name: name.into(), ..Generic::default()}
+    }
+}
+
+/// The direction of a port, as seen from inside the entity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+    #[default]
+    InOut,
+}
+
+/// The kind of signal a port carries.
+/// Serialized as a plain string, anything else than electrical or logic is a custom discipline.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Nature {
+    #[default]
+    Electrical,
+    Logic,
+    Custom(String),
+}
+
+impl From<String> for Nature {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "electrical" => Nature::Electrical,
+            "logic" => Nature::Logic,
+            _ => Nature::Custom(name),
+        }
+    }
+}
+
+impl From<Nature> for String {
+    fn from(nature: Nature) -> Self {
+        match nature {
+            Nature::Electrical => "electrical".into(),
+            Nature::Logic => "logic".into(),
+            Nature::Custom(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RangeDirection {
+    To,
+    Downto,
+}
+
+/// The index range of a bus, like `N-1 downto 0`.
+/// The bounds are expressions of the generics of the entity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BusRange {
+    pub left: String,
+    pub direction: RangeDirection,
+    pub right: String,
+}
+
+impl BusRange {
+    pub fn new(left: &str, direction: RangeDirection, right: &str) -> BusRange {
+        BusRange {left: left.into(), direction, right: right.into()}
+    }
+
+    /// The indices from left to right, given the generic values
+    pub fn indices(&self, generics: &IndexMap<String, String>) -> Result<Vec<i64>, String> {
+        let var = |name: &str| generics.get(name).and_then(|val| units::parse_si(val, None));
+        let bound = |expr: &str| {
+            let val = expr::eval(expr, &var)?;
+            if val.fract() != 0.0 {
+                return Err(format!("bus bound {} = {} is not an integer", expr, val));
+            }
+            Ok(val as i64)
+        };
+        let (left, right) = (bound(&self.left)?, bound(&self.right)?);
+        Ok(match self.direction {
+            RangeDirection::To => (left..=right).collect(),
+            RangeDirection::Downto => (right..=left).rev().collect(),
+        })
+    }
+}
+
+/// A port of an entity
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Port {
+    pub name: String,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default)]
+    pub nature: Nature,
+    /// The bus range if this port is a vector
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<BusRange>,
+}
+
+impl Port {
+    pub fn new(name: &str, direction: Direction, nature: Nature) -> Port {
+        Port {name: name.into(), direction, nature, range: None}
+    }
+
+    /// The scalar nodes of this port, given the net it connects to.
+    /// A bus connects either to a list of nets separated by spaces,
+    /// or to the nets `net_i` for each index `i`.
+    pub fn nodes(&self, net: &str, generics: &IndexMap<String, String>) -> Result<Vec<String>, String> {
+        let indices = match &self.range {
+            None => return Ok(vec![net.into()]),
+            Some(range) => range.indices(generics)?,
+        };
+        let nets: Vec<&str> = net.split_whitespace().collect();
+        if nets.len() == indices.len() {
+            Ok(nets.into_iter().map(String::from).collect())
+        } else if nets.len() == 1 {
+            Ok(indices.iter().map(|i| format!("{}_{}", net, i)).collect())
+        } else {
+            Err(format!("{} nets for the {} bits of {}", nets.len(), indices.len(), self.name))
+        }
+    }
+}
+
+impl From<&str> for Port {
+    fn from(name: &str) -> Self {
+        Port {name: name.into(), ..Port::default()}
+    }
+}
+
+/// A reference to an entity by name.
+/// Serialized as just the name, and resolved against a `Library` after loading.
+#[derive(Clone, Default)]
+pub struct EntityRef {
+    name: String,
+    entity: Option<Rc<Entity>>,
+}
+
+impl EntityRef {
+    /// An unresolved reference to the named entity
+    pub fn new(name: &str) -> EntityRef {
+        EntityRef {name: name.into(), entity: None}
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// A reference without name, standing for the instantiated entity in configuration rules
+    pub fn is_empty(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    /// The referenced entity, or an Unresolved error if it has not been resolved
+    pub fn get(&self) -> Result<&Rc<Entity>, CodeError> {
+        self.entity.as_ref().ok_or_else(|| ErrorKind::Unresolved(self.name.clone()).into())
+    }
+
+    /// Look up the entity by name in the library
+    pub fn resolve(&mut self, lib: &Library) -> Result<(), CodeError> {
+        let ent = lib.get(&self.name).ok_or_else(|| ErrorKind::NoEntity(self.name.clone()))?;
+        self.entity = Some(ent.clone());
+        Ok(())
+    }
+}
+
+impl From<Rc<Entity>> for EntityRef {
+    fn from(ent: Rc<Entity>) -> Self {
+        EntityRef {name: ent.name.clone(), entity: Some(ent)}
+    }
+}
+
+impl Serialize for EntityRef {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+impl<'de> Deserialize<'de> for EntityRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(EntityRef {name: String::deserialize(deserializer)?, entity: None})
+    }
+}
+
+/// A collection of entities, by name.
+/// Entities can only reference entities that are already in the library,
+/// so the insertion order is also a valid dependency order.
+#[derive(Default)]
+pub struct Library {
+    entities: IndexMap<String, Rc<Entity>>,
+    /// Warnings about the entities, found when they were inserted
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Library {
+    pub fn new() -> Library {
+        Library::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Rc<Entity>> {
+        self.entities.get(name)
+    }
+
+    pub fn entities(&self) -> impl Iterator<Item=&Rc<Entity>> {
+        self.entities.values()
+    }
+
+    /// The warnings found while inserting entities, like ports that a reference template does not use
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Add an entity, resolving the entity references of its instances against this library.
+    /// Errors if a reference template uses something the entity does not have,
+    /// and keeps the other findings of the template checks as `diagnostics`.
+    pub fn insert(&mut self, mut ent: Entity) -> Result<Rc<Entity>, CodeError> {
+        for g in &ent.generic {
+            if let Some(default) = &g.default {
+                g.check(default).map_err(|message| ErrorKind::InvalidGeneric {entity: ent.name.clone(), message})?;
+            }
+        }
+        let (errors, warnings): (Vec<_>, Vec<_>) = template::check_entity(&ent).into_iter()
+            .partition(|(severity, _)| *severity == Severity::Error);
+        if let Some((_, kind)) = errors.into_iter().next() {
+            return Err(kind.into());
+        }
+        for arch in ent.archs.values_mut() {
+            if let Arch::Schematic(sch) = arch {
+                for inst in sch.instances.values_mut() {
+                    inst.entity.resolve(self)?;
+                }
+            }
+        }
+        let ent = Rc::from(ent);
+        self.entities.insert(ent.name.clone(), ent.clone());
+        self.diagnostics.extend(warnings.into_iter().map(|(_, kind)| Diagnostic::warning(kind.into())));
+        Ok(ent)
+    }
+
+    /// Add entities in any order, inserting each one after the entities it instantiates
+    pub fn extend(&mut self, ents: Vec<Entity>) -> Result<(), CodeError> {
+        let mut pending = ents;
+        while !pending.is_empty() {
+            let (ready, blocked): (Vec<Entity>, Vec<Entity>) = pending.into_iter()
+                .partition(|ent| ent.dependencies().all(|dep| self.entities.contains_key(dep)));
+            if ready.is_empty() {
+                return Err(ErrorKind::Recursive(blocked.into_iter().map(|ent| ent.name).collect()).into());
+            }
+            for ent in ready {
+                self.insert(ent)?;
+            }
+            pending = blocked;
+        }
+        Ok(())
+    }
+}
+
+impl Entity {
+    /// The generic values of an instance of this entity, with defaults filled in.
+    /// Errors if a value is missing, unknown, or does not match its declaration.
+    pub fn generic_values(&self, genericmap: &IndexMap<String, String>) -> Result<IndexMap<String, String>, CodeError> {
+        let mut errors = Vec::new();
+        let values = self.collect_generic_values(genericmap, &mut errors);
+        match errors.into_iter().next() {
+            Some(kind) => Err(kind.into()),
+            None => Ok(values),
+        }
+    }
+
+    /// The generic values like `generic_values`, with every error added to `errors`
+    /// instead of stopping at the first one
+    pub(crate) fn collect_generic_values(&self, genericmap: &IndexMap<String, String>, errors: &mut Vec<ErrorKind>) -> IndexMap<String, String> {
+        for name in genericmap.keys().filter(|name| !self.generic.iter().any(|g| &g.name == *name)) {
+            errors.push(ErrorKind::UnknownGeneric {entity: self.name.clone(), generic: name.clone()});
+        }
+        let mut values = IndexMap::new();
+        for g in &self.generic {
+            match genericmap.get(&g.name).or(g.default.as_ref()) {
+                Some(val) => {
+                    if let Err(message) = g.check(val) {
+                        errors.push(ErrorKind::InvalidGeneric {entity: self.name.clone(), message});
+                    }
+                    values.insert(g.name.clone(), val.clone());
+                }
+                None => errors.push(ErrorKind::MissingGeneric {entity: self.name.clone(), generic: g.name.clone()}),
+            }
+        }
+        values
+    }
+
+    /// The generics with a default value
+    pub fn generic_defaults(&self) -> IndexMap<String, String> {
+        self.generic.iter()
+            .filter_map(|g| Some((g.name.clone(), g.default.clone()?)))
+            .collect()
+    }
+
+    /// The nodes each port of an instance connects to, with buses expanded to scalar nodes.
+    /// Errors if a port is not connected, or the instance connects a port the entity does not have.
+    pub fn port_nodes(&self, portmap: &IndexMap<String, String>, generics: &IndexMap<String, String>) -> Result<IndexMap<String, Vec<String>>, CodeError> {
+        let mut errors = Vec::new();
+        let nodes = self.collect_port_nodes(portmap, generics, &mut errors);
+        match errors.into_iter().next() {
+            Some(kind) => Err(kind.into()),
+            None => Ok(nodes),
+        }
+    }
+
+    /// The port nodes like `port_nodes`, with every error added to `errors`
+    pub(crate) fn collect_port_nodes(&self, portmap: &IndexMap<String, String>, generics: &IndexMap<String, String>, errors: &mut Vec<ErrorKind>) -> IndexMap<String, Vec<String>> {
+        let mut nodes = IndexMap::new();
+        for p in &self.port {
+            match portmap.get(&p.name).map(|net| p.nodes(net, generics)) {
+                Some(Ok(pnodes)) => {
+                    nodes.insert(p.name.clone(), pnodes);
+                }
+                Some(Err(message)) => errors.push(ErrorKind::Bus(message)),
+                None => errors.push(ErrorKind::MissingPort {entity: self.name.clone(), port: p.name.clone()}),
+            }
+        }
+        for port in portmap.keys().filter(|name| !self.port.iter().any(|p| &p.name == *name)) {
+            errors.push(ErrorKind::UnknownPort {entity: self.name.clone(), port: port.clone()});
+        }
+        nodes
+    }
+
+    /// The names of the entities instantiated in the schematics of this entity
+    pub fn dependencies(&self) -> impl Iterator<Item=&str> {
+        self.archs.values().flat_map(|arch| match arch {
+            Arch::Schematic(sch) => sch.instances.values().map(|inst| inst.entity.name()).collect(),
+            Arch::Code(_) => Vec::new(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct LibraryOut<'a> {
+    entity: Vec<&'a Entity>,
+}
+
+#[derive(Deserialize)]
+struct LibraryIn {
+    entity: Vec<Entity>,
+}
+
+impl Serialize for Library {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        LibraryOut {entity: self.entities.values().map(|ent| ent.as_ref()).collect()}.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Library {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = LibraryIn::deserialize(deserializer)?;
+        let mut lib = Library::new();
+        lib.extend(data.entity).map_err(serde::de::Error::custom)?;
+        Ok(lib)
+    }
+}
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "", deserialize = "S: Default"))]
 pub struct Configuration<S: Simulator> {
     /// The simulator to target
+    #[serde(skip)]
     pub sim: S,
-    /// The entity to synthesize
-    pub ent: Rc<Entity>,
+    /// The entity to synthesize.
+    /// Left empty in a rule for an instance, the instantiated entity is used.
+    #[serde(default, skip_serializing_if = "EntityRef::is_empty")]
+    pub ent: EntityRef,
     /// The architecture to use for this entity.
     /// If None, a default from all is used, or the first that matches the simulator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub arch: Option<String>,
-    // The configuration for a sub-instance
-    pub for_inst: RefCell<HashMap<String, Configuration<S>>>,
+    /// When binding an instance to a different entity, maps the ports of `ent`
+    /// to the ports of the instantiated entity, or to a fixed net in double quotes.
+    /// If empty, ports are bound by name.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub portmap: IndexMap<String, String>,
+    /// Maps the generics of `ent` to the generics of the instantiated entity, or to a value in double quotes.
+    /// If empty, generics are bound by name.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub genericmap: IndexMap<String, String>,
+    /// The configuration for sub-instances.
+    /// Keys are instance names, paths like `buf/inv1`, globs like `buf/*` or `**/nmos*`, or `others`.
+    /// For an instance, its name wins over a glob, which wins over `others`.
+    /// Rules in an enclosing configuration override those of nested ones.
+    #[serde(default)]
+    pub for_inst: IndexMap<String, Configuration<S>>,
     /// For all Entity => Arch.
     /// Weakest specification.
-    pub all: HashMap<String, String>,
+    #[serde(default)]
+    pub all: IndexMap<String, String>,
+    /// The preferred abstraction levels, most preferred first,
+    /// for architectures that are not chosen explicitly.
+    /// Inherited by sub-instances whose rule does not state its own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub levels: Vec<Level>,
+    /// The configurations derived for each instance from the rules above
+    #[serde(skip)]
+    cache: RefCell<IndexMap<String, Configuration<S>>>,
+    /// The path of the instance this configuration is derived for, from the configured entity
+    #[serde(skip)]
+    path: Vec<String>,
 }
 
 impl<S> Configuration<S> where S: Simulator {
-    fn get_arch(&self) -> Option<&Arch> {
+    fn get_arch(&self) -> Result<&Arch, CodeError> {
+        self.get_named_arch().map(|(_, arch)| arch)
+    }
+
+    /// The selected architecture and its name
+    pub(crate) fn get_named_arch(&self) -> Result<(&String, &Arch), CodeError> {
+        let ent = self.ent.get()?;
+        let named = |arch: &String| ent.archs.get_key_value(arch)
+            .ok_or_else(|| ErrorKind::NoArch {entity: ent.name.clone(), arch: arch.clone()}.into());
         if let Some(arch) = &self.arch { // directly specified
-            self.ent.archs.get(arch)
-        } else if let Some(arch) = self.all.get(&self.ent.name) { // entity specified
-            self.ent.archs.get(arch)
-        } else { // find the first one that supports this sim
-            for (_name, arch) in &self.ent.archs {
-                match arch {
-                    Arch::Code(cda) => if self.sim.get_dialect(cda).is_some() {
-                        return Some(arch);
+            named(arch)
+        } else if let Some(arch) = self.all.get(&ent.name) { // entity specified
+            named(arch)
+        } else {
+            let supported = |(_, arch): &(&String, &Arch)| match arch {
+                Arch::Code(cda) => self.sim.get_dialect(cda).is_some(),
+                Arch::Schematic(_) => true,
+            };
+            // the first one at the most preferred level,
+            // or else the first one that supports this sim, in insertion order
+            self.levels.iter()
+                .find_map(|&level| ent.archs.iter().filter(supported).find(|(_, arch)| arch.level() == Some(level)))
+                .or_else(|| ent.archs.iter().find(supported))
+                .ok_or_else(|| ErrorKind::NoDialect {entity: ent.name.clone(), arch: None, simulator: self.sim.name().into()}.into())
+        }
+    }
+
+    /// The dialect of a code architecture for the simulator
+    fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Result<&'a CodeArch, CodeError> {
+        self.sim.get_dialect(arch).ok_or_else(|| ErrorKind::NoDialect {
+            entity: self.ent.name().into(),
+            arch: self.get_named_arch().ok().map(|(name, _)| name.clone()),
+            simulator: self.sim.name().into(),
+        }.into())
+    }
+
+    /// The configured entity and the instance names down to this configuration
+    fn full_path(&self) -> Vec<String> {
+        if self.path.is_empty() {
+            vec![self.ent.name().into()]
+        } else {
+            self.path.clone()
+        }
+    }
+
+    /// Where in the hierarchy this configuration is, to locate errors
+    pub fn context(&self) -> Context {
+        Context {
+            path: self.full_path(),
+            entity: self.ent.name().into(),
+            arch: self.arch.clone().or_else(|| self.get_named_arch().ok().map(|(name, _)| name.clone())),
+            simulator: self.sim.name().into(),
+        }
+    }
+
+    /// Run a synthesis step, locating its errors in this configuration
+    /// unless they are located deeper already
+    pub(crate) fn located<T>(&self, step: impl FnOnce() -> Result<T, CodeError>) -> Result<T, CodeError> {
+        step().map_err(|e| e.within(|| self.context()))
+    }
+
+    /// Check the whole tree below this configuration, collecting every error and warning
+    /// with its location instead of stopping at the first, like the diagnostics of vhdl_lang.
+    /// When no errors are added, synthesizing the definition succeeds.
+    /// The template of a code architecture is checked once, at the first instance that uses it.
+    pub fn diagnose(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let errors = |diagnostics: &Vec<Diagnostic>| diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+        let before = errors(diagnostics);
+        self.diagnose_tree(diagnostics, &mut IndexSet::new());
+        // anything the checks do not cover, reported by the simulator itself
+        if errors(diagnostics) == before {
+            if let Err(e) = self.definition() {
+                diagnostics.push(Diagnostic::error(e));
+            }
+        }
+    }
+
+    fn diagnose_tree(&self, diagnostics: &mut Vec<Diagnostic>, templates: &mut IndexSet<(String, String)>) {
+        let sch = match self.get_named_arch() {
+            Ok((_, Arch::Schematic(sch))) => sch,
+            Ok((name, Arch::Code(arch))) => {
+                // the template of the dialect this simulator uses
+                let unchecked = templates.insert((self.ent.name().into(), name.clone()));
+                if let (true, Ok(ent), Ok(code)) = (unchecked, self.ent.get(), self.get_dialect(arch)) {
+                    for (dialect, _) in arch.dialects.iter().filter(|(_, dialect)| std::ptr::eq(*dialect, code)) {
+                        for (severity, kind) in template::check(ent, dialect, &code.reference) {
+                            let error = CodeError::from(kind).within(|| self.context());
+                            diagnostics.push(Diagnostic {severity, error});
+                        }
                     }
-                    Arch::Schematic(_) => return Some(arch)
+                }
+                if let Err(e) = self.definition() {
+                    diagnostics.push(Diagnostic::error(e));
+                }
+                return;
+            }
+            Err(e) => {
+                diagnostics.push(Diagnostic::error(e.within(|| self.context())));
+                return;
+            }
+        };
+        for (name, inst) in &sch.instances {
+            let subconf = self.get_conf(name, inst);
+            let ent = match inst.entity.get() {
+                Ok(ent) => ent,
+                Err(e) => {
+                    diagnostics.push(Diagnostic::error(e.within(|| subconf.context())));
+                    continue;
+                }
+            };
+            // the same checks as netlisting the instance, but all of them
+            let mut found = Vec::new();
+            let generics = ent.collect_generic_values(&inst.genericmap, &mut found);
+            ent.collect_port_nodes(&inst.portmap, &generics, &mut found);
+            let valid = found.is_empty();
+            for kind in found {
+                diagnostics.push(Diagnostic::error(CodeError::from(kind).within(|| subconf.context())));
+            }
+            let before = diagnostics.len();
+            subconf.diagnose_tree(diagnostics, templates);
+            // the reference would only repeat the first of the errors found so far
+            if valid && diagnostics[before..].iter().all(|d| d.severity == Severity::Warning) {
+                if let Err(e) = subconf.instance_reference(name, inst) {
+                    diagnostics.push(Diagnostic::error(e));
                 }
             }
-            None
         }
     }
 
-    /// Gets the configuration for a certain instance.
-    /// If no configuration is given for this instance,
-    /// a default configuration is created with a copy of
-    /// the per-entity defaults
-    fn get_conf(&self, name: &str, inst: &Instance) -> Ref<Configuration<S>> {
-        self.for_inst.borrow_mut().entry(name.into()).or_insert_with(|| Configuration {
-            sim: self.sim,
-            ent: inst.entity.clone(),
+    /// A configuration of the entity without any rules
+    pub fn new(sim: S, ent: EntityRef) -> Configuration<S> {
+        Configuration {
+            sim,
+            ent,
             arch: None,
-            for_inst: RefCell::from(HashMap::new()),
+            portmap: IndexMap::new(),
+            genericmap: IndexMap::new(),
+            for_inst: IndexMap::new(),
+            all: IndexMap::new(),
+            levels: Vec::new(),
+            cache: RefCell::default(),
+            path: Vec::new(),
+        }
+    }
+
+    /// The same rules targeting another simulator
+    pub fn retarget<T: Simulator>(&self, sim: T) -> Configuration<T> {
+        Configuration {
+            sim,
+            ent: self.ent.clone(),
+            arch: self.arch.clone(),
+            portmap: self.portmap.clone(),
+            genericmap: self.genericmap.clone(),
+            for_inst: self.for_inst.iter().map(|(key, conf)| (key.clone(), conf.retarget(sim))).collect(),
             all: self.all.clone(),
+            levels: self.levels.clone(),
+            cache: RefCell::default(),
+            path: self.path.clone(),
+        }
+    }
+
+    /// Gets the configuration for a certain instance.
+    /// It is derived from the best matching rule in for_inst,
+    /// or a default configuration of the instantiated entity.
+    /// Rules for deeper instances are passed down with the first segment stripped,
+    /// as are the per-entity defaults.
+    fn get_conf(&self, name: &str, inst: &Instance) -> Ref<'_, Configuration<S>> {
+        if !self.cache.borrow().contains_key(name) {
+            let conf = self.derive_conf(name, inst);
+            self.cache.borrow_mut().insert(name.into(), conf);
+        }
+        Ref::map(self.cache.borrow(), |cache| &cache[name])
+    }
+
+    fn derive_conf(&self, name: &str, inst: &Instance) -> Configuration<S> {
+        // `**` also matches zero levels, so `**/rest` applies here as `rest`
+        let rules = self.for_inst.iter().flat_map(|(key, rule)| {
+            let here = key.strip_prefix("**/").map(|rest| (rest, rule));
+            std::iter::once((key.as_str(), rule)).chain(here)
         });
-        Ref::map(self.for_inst.borrow(), |inst| &inst[name.into()])
+        let mut best: Option<(u8, &Configuration<S>)> = None;
+        let mut nested = IndexMap::new();
+        for (key, rule) in rules {
+            match key.split_once('/') {
+                Some(("**", _)) => {
+                    nested.insert(key.to_string(), rule);
+                }
+                Some((head, rest)) => if glob(head, name) {
+                    nested.insert(rest.to_string(), rule);
+                }
+                None => {
+                    let rank = match key {
+                        _ if key == name => 0,
+                        "others" => 2,
+                        _ if glob(key, name) => 1,
+                        _ => continue,
+                    };
+                    if best.is_none_or(|(best, _)| rank < best) {
+                        best = Some((rank, rule));
+                    }
+                }
+            }
+        }
+        let mut conf = match best {
+            Some((_, rule)) => rule.clone(),
+            None => Configuration::new(self.sim, inst.entity.clone()),
+        };
+        conf.sim = self.sim;
+        if conf.ent.is_empty() {
+            conf.ent = inst.entity.clone();
+        }
+        conf.cache = RefCell::default();
+        conf.path = self.full_path();
+        conf.path.push(name.into());
+        // enclosing rules override nested ones
+        for (key, rule) in nested {
+            conf.for_inst.insert(key, rule.clone());
+        }
+        for (ent, arch) in &self.all {
+            conf.all.insert(ent.clone(), arch.clone());
+        }
+        if conf.levels.is_empty() {
+            conf.levels = self.levels.clone();
+        }
+        conf
+    }
+
+    /// The reference to an instance in a schematic.
+    /// When bound to a different entity, the generics of the instantiated entity
+    /// are filled with their defaults, so the binding can refer to them.
+    pub(crate) fn instance_reference(&self, name: &str, inst: &Instance) -> Result<String, CodeError> {
+        self.located(|| self.check_binding(inst))?;
+        if self.genericmap.is_empty() {
+            self.reference(name, &inst.genericmap, &inst.portmap)
+        } else {
+            let generics = inst.entity.get()?.generic_values(&inst.genericmap)?;
+            self.reference(name, &generics, &inst.portmap)
+        }
+    }
+
+    /// The generics of an instance, translated to the bound entity,
+    /// for languages without configurations that instantiate the bound entity directly
+    pub(crate) fn instance_generics(&self, inst: &Instance) -> Result<IndexMap<String, String>, CodeError> {
+        self.check_binding(inst)?;
+        if self.genericmap.is_empty() {
+            Ok(inst.genericmap.clone())
+        } else {
+            Ok(Self::bind(&self.genericmap, &inst.entity.get()?.generic_values(&inst.genericmap)?))
+        }
+    }
+
+    /// The ports of an instance, translated to the bound entity
+    pub(crate) fn instance_ports(&self, inst: &Instance) -> Result<IndexMap<String, String>, CodeError> {
+        self.check_binding(inst)?;
+        Ok(Self::bind(&self.portmap, &inst.portmap))
+    }
+
+    /// The definitions of the instances of a schematic, each one once, in dependency order
+    pub(crate) fn instance_definitions(&self, sch: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+        let mut defs = IndexSet::new();
+        for (name, inst) in &sch.instances {
+            // add to ordered set to avoid duplicates but maintain dependency order
+            defs.extend(self.get_conf(name, inst).definition()?)
+        }
+        Ok(defs)
+    }
+
+    /// The nets of a schematic of this entity that are not its ports, for languages that declare them.
+    /// The bits of a bus connected to a list of nets are scalars,
+    /// and a bit or part select like `a[0]` is declared with its bus.
+    pub(crate) fn internal_nets<'a>(&self, sch: &'a Schematic) -> Result<IndexMap<String, Net<'a>>, CodeError> {
+        let ent = self.ent.get()?;
+        let mut nets: IndexMap<String, Net<'a>> = IndexMap::new();
+        let new = |nets: &IndexMap<String, Net<'a>>, net: &str| !nets.contains_key(net) && !ent.port.iter().any(|q| q.name == net);
+        for (name, inst) in &sch.instances {
+            self.get_conf(name, inst).located(|| {
+                let child = inst.entity.get()?;
+                let generics = child.generic_values(&inst.genericmap)?;
+                for port in &child.port {
+                    let net = match inst.portmap.get(&port.name) {
+                        Some(net) => net,
+                        None => continue,
+                    };
+                    if is_concat(net) {
+                        for bit in net.split_whitespace().filter(|bit| new(&nets, bit)).collect::<Vec<&str>>() {
+                            nets.insert(bit.into(), Net {port, range: None});
+                        }
+                    } else if !net.contains(['[', '(']) && new(&nets, net) {
+                        let range = match &port.range {
+                            Some(range) => match range.indices(&generics).map_err(ErrorKind::Bus)?.as_slice() {
+                                [left, .., right] => Some((*left, *right)),
+                                [bit] => Some((*bit, *bit)),
+                                [] => return Err(ErrorKind::Bus(format!("empty range of {}", port.name)).into()),
+                            },
+                            None => None,
+                        };
+                        nets.insert(net.clone(), Net {port, range});
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(nets)
+    }
+
+    /// Check that the actuals of the binding are ports and generics of the instantiated entity,
+    /// so a typo in a binding is not taken as a net or value
+    pub(crate) fn check_binding(&self, inst: &Instance) -> Result<(), CodeError> {
+        let component = inst.entity.get()?;
+        let unquoted = |map: &IndexMap<String, String>| map.values().filter(|actual| quoted(actual).is_none()).cloned().collect::<Vec<String>>();
+        if let Some(port) = unquoted(&self.portmap).into_iter().find(|actual| !component.port.iter().any(|p| &p.name == actual)) {
+            return Err(ErrorKind::UnknownPort {entity: component.name.clone(), port}.into());
+        }
+        if let Some(generic) = unquoted(&self.genericmap).into_iter().find(|actual| !component.generic.iter().any(|g| &g.name == actual)) {
+            return Err(ErrorKind::UnknownGeneric {entity: component.name.clone(), generic}.into());
+        }
+        Ok(())
+    }
+
+    /// Translate the maps of an instance to the bound entity.
+    /// Quoted actuals are taken literally, so a port can be tied to a fixed net or a generic to a value.
+    /// Actuals the instance does not set are left out.
+    fn bind(binding: &IndexMap<String, String>, instmap: &IndexMap<String, String>) -> IndexMap<String, String> {
+        if binding.is_empty() {
+            return instmap.clone();
+        }
+        binding.iter()
+            .filter_map(|(formal, actual)| Some((formal.clone(), quoted(actual).or_else(|| instmap.get(actual).map(String::as_str))?.to_string())))
+            .collect()
+    }
+
+    /// The library files used anywhere below this configuration
+    pub fn libraries(&self) -> Result<IndexSet<PathBuf>, CodeError> {
+        self.located(|| match self.get_arch()? {
+            Arch::Code(arch) => Ok(self.get_dialect(arch)?.definition()?.into_iter()
+                .filter_map(|def| match def {
+                    Definition::Library(path) => Some(path),
+                    _ => None,
+                })
+                .collect()),
+            Arch::Schematic(sch) => {
+                let mut libs = IndexSet::new();
+                for (name, inst) in &sch.instances {
+                    libs.extend(self.get_conf(name, inst).libraries()?);
+                }
+                Ok(libs)
+            }
+        })
+    }
+
+    /// The code of this configuration in the `entry` file, with copies of the libraries it uses
+    pub fn bundle(&self, entry: &str) -> Result<Bundle, CodeError> {
+        let mut bundle = Bundle::new(entry);
+        let mut code = String::new();
+        for def in self.definition()? {
+            if let Definition::Code(def) = def {
+                code.push_str(&def);
+                if !def.ends_with('\n') {
+                    code.push('\n');
+                }
+            }
+        }
+        bundle.add_entry(code, self.libraries()?)?;
+        Ok(bundle)
+    }
+
+    /// Resolve the entity references of this configuration and its sub-instances
+    /// against the library, as needed after deserializing.
+    pub fn resolve(&mut self, lib: &Library) -> Result<(), CodeError> {
+        if !self.ent.is_empty() {
+            self.ent.resolve(lib)?;
+        }
+        for conf in self.for_inst.values_mut() {
+            conf.resolve(lib)?;
+        }
+        Ok(())
+    }
+}
+
+/// A net inside a schematic that is not a port of its entity
+pub(crate) struct Net<'a> {
+    /// The first port of an instance that connects to it, which gives its type
+    pub port: &'a Port,
+    /// The first and last index if it is a whole bus
+    pub range: Option<(i64, i64)>,
+}
+
+/// A bus connected to a list of nets separated by spaces
+pub(crate) fn is_concat(net: &str) -> bool {
+    net.split_whitespace().nth(1).is_some()
+}
+
+/// A generic value, to be written in the syntax of a language
+#[derive(Debug, PartialEq)]
+pub(crate) enum Literal<'a> {
+    /// A braced `{expr}`, taken as an expression of the language
+    Expr(&'a str),
+    /// A real or a SPICE style quantity
+    Real(f64),
+    /// Text without the quotes it may have
+    String(&'a str),
+    /// Anything else, taken as is
+    Other(&'a str),
+}
+
+/// A checked generic value of an entity, for the backends to format in their language.
+/// Quantities are read with the unit of the generic, like `1.5uF`.
+pub(crate) fn literal<'a>(ent: &Entity, g: &Generic, val: &'a str) -> Result<Literal<'a>, CodeError> {
+    if let Some(expr) = val.strip_prefix('{').and_then(|val| val.strip_suffix('}')) {
+        return Ok(Literal::Expr(expr));
+    }
+    let invalid = |message| ErrorKind::InvalidGeneric {entity: ent.name.clone(), message};
+    g.check(val).map_err(invalid)?;
+    Ok(match g.kind {
+        GenericKind::Real | GenericKind::Quantity => match units::parse_si(val, g.unit.as_deref()) {
+            Some(num) => Literal::Real(num),
+            None => return Err(invalid(format!("{} = {} is not a valid {:?}", g.name, val, g.kind)).into()),
+        },
+        GenericKind::String => Literal::String(quoted(val).unwrap_or(val)),
+        GenericKind::Integer => Literal::Other(val.trim()),
+    })
+}
+
+/// The literal in a binding actual in double quotes, like `"vdd"`
+pub(crate) fn quoted(actual: &str) -> Option<&str> {
+    actual.strip_prefix('"').and_then(|actual| actual.strip_suffix('"'))
+}
+
+/// Match an instance name against a pattern where `*` is any sequence and `?` any character
+fn glob(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).filter(|&i| name.is_char_boundary(i)).any(|i| glob(&pattern[1..], &name[i..])),
+        Some(c) => {
+            let mut chars = name.chars();
+            match chars.next() {
+                Some(n) if c == '?' || c == n => glob(&pattern[c.len_utf8()..], chars.as_str()),
+                _ => false,
+            }
+        }
     }
 }
 
 // TODO instances and schematics require a complete rework for GUI interface
+#[derive(Serialize, Deserialize)]
 pub struct Instance {
-    pub portmap: HashMap<String, String>,
-    pub genericmap: HashMap<String, String>,
+    pub portmap: IndexMap<String, String>,
+    pub genericmap: IndexMap<String, String>,
     pub x: i64,
     pub y: i64,
-    pub entity: Rc<Entity>,
+    pub entity: EntityRef,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Schematic {
     pub toplevel: bool,
-    pub instances: HashMap<String, Instance>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<Level>,
+    pub instances: IndexMap<String, Instance>,
 }
 
 /// Represents a component that can be expressed in code.
@@ -105,45 +1017,34 @@ pub struct Schematic {
 pub trait Code {
     /// The definition of this component.
     /// The .subckt or architecture code
-    fn definition(&self) -> Result<IndexSet<Definition>, CodeError> { Err(CodeError::DialectError) }
-    /// The declaration, not used in all languages, and by default will return a DialectError.
+    fn definition(&self) -> Result<IndexSet<Definition>, CodeError> { Err(ErrorKind::Unsupported("no definition".into()).into()) }
+    /// The declaration, not used in all languages, and by default an Unsupported error.
     /// In VHDL this is the component declaration in the instantiating architecture.
     /// Component instantiation is required for configurations
-    fn declaration(&self) -> Result<String, CodeError> { Err(CodeError::DialectError) }
+    fn declaration(&self) -> Result<String, CodeError> { Err(ErrorKind::Unsupported("no declaration".into()).into()) }
     /// The reference to a component given the instance name, and the ports and parameters to pass to the component.
     /// This is used to instantiate a component in another one.
-    fn reference(&self, _name: &str, _genericmap: &HashMap<String, String>, _portmap: &HashMap<String, String>) -> Result<String, CodeError> { Err(CodeError::DialectError) }
+    fn reference(&self, _name: &str, _genericmap: &IndexMap<String, String>, _portmap: &IndexMap<String, String>) -> Result<String, CodeError> { Err(ErrorKind::Unsupported("no reference".into()).into()) }
 }
 
-#[derive(Debug)]
-pub enum CodeError {
-    DialectError,
-    CompileError(String),
-    TemplateError(handlebars::TemplateRenderError),
-}
-
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Definition {
     Code(String),
     Library(PathBuf),
     Primitive,
 }
 
-impl From<handlebars::TemplateRenderError> for CodeError {
-    fn from(error: handlebars::TemplateRenderError) -> Self {
-        CodeError::TemplateError(error)
-    }
-}
-
 #[derive(Serialize)]
 struct RefArgs<'a> {
     name: &'a str,
-    generic: &'a HashMap<String, String>,
-    port: &'a HashMap<String, String>,
+    generic: &'a IndexMap<String, String>,
+    port: &'a IndexMap<String, String>,
 }
 
 /// Contains a definition in some language
-/// and a Handlebars template for referencing the definition
+/// and a Handlebars template for referencing the definition, see `template` for its helpers
+#[derive(Serialize, Deserialize)]
 pub struct CodeArch {
     pub definition: Definition,
     pub reference: String,
@@ -151,142 +1052,425 @@ pub struct CodeArch {
 
 impl Code for CodeArch {
     fn definition(&self) -> Result<IndexSet<Definition>, CodeError> { Ok(indexset!{self.definition.clone()}) }
-    fn reference(&self, name: &str, genericmap: &HashMap<String, String>, portmap: &HashMap<String, String>) -> Result<String, CodeError> {
-        let handlebars = Handlebars::new();
-        let varmap = RefArgs {name: name, generic: genericmap, port: portmap};
-        let reference = handlebars.render_template(&self.reference, &varmap)?;
-        Ok(reference)
+    fn reference(&self, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        template::render(&self.reference, &RefArgs {name, generic: genericmap, port: portmap})
     }
 }
 
 /// Contains multiple dialectso of a given subcircuit/model
 /// Maps from a spice dialect to a definition
+#[derive(Default, Serialize, Deserialize)]
 pub struct CodeDialectArch {
-    pub dialects: HashMap<String, CodeArch>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<Level>,
+    pub dialects: IndexMap<String, CodeArch>,
 }
 
 impl CodeDialectArch {
     pub fn new() -> CodeDialectArch {
-        CodeDialectArch {dialects: HashMap::new()}
+        CodeDialectArch {level: None, dialects: IndexMap::new()}
+    }
+}
+
+/// A source file, as loaded into a simulator server.
+/// Libraries are copied byte for byte, so the contents need not be text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct File {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+/// The files of a synthesized design, as passed to `loadFiles` of a simulator server,
+/// and the name of the one the simulator should run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bundle {
+    pub entry: String,
+    pub files: Vec<File>,
+    /// The bundled name of each library on the local disk
+    #[serde(skip)]
+    libraries: IndexMap<PathBuf, String>,
+}
+
+impl Bundle {
+    /// An empty bundle that will run the named file
+    pub fn new(entry: &str) -> Bundle {
+        Bundle {entry: entry.into(), files: Vec::new(), libraries: IndexMap::new()}
+    }
+
+    /// Add a generated file
+    pub fn add(&mut self, name: &str, contents: String) {
+        self.files.push(File {name: name.into(), contents: contents.into_bytes()});
+    }
+
+    pub fn get(&self, name: &str) -> Option<&File> {
+        self.files.iter().find(|f| f.name == name)
+    }
+
+    /// Add a copy of a library file, and return its name in the bundle.
+    /// Libraries are bundled by file name, with a number added if that is taken.
+    /// The libraries it includes in turn are bundled too, relative to its directory.
+    pub fn add_library(&mut self, path: &Path) -> Result<String, CodeError> {
+        if let Some(name) = self.libraries.get(path) {
+            return Ok(name.clone());
+        }
+        let contents = std::fs::read(path)
+            .map_err(|error| ErrorKind::Io {path: path.into(), error})?;
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("lib");
+        let ext = path.extension().and_then(|s| s.to_str()).map_or(String::new(), |ext| format!(".{}", ext));
+        let mut name = format!("{}{}", stem, ext);
+        let mut n = 1;
+        while self.get(&name).is_some() || name == self.entry {
+            name = format!("{}_{}{}", stem, n, ext);
+            n += 1;
+        }
+        // taken before the includes, so a library that includes itself ends here
+        self.add(&name, String::new());
+        self.libraries.insert(path.into(), name.clone());
+        // the includes of a binary library are not ours to rewrite
+        let contents = match String::from_utf8(contents) {
+            Ok(code) => self.bundle_includes(&code, path.parent())?.into_bytes(),
+            Err(err) => err.into_bytes(),
+        };
+        if let Some(file) = self.files.iter_mut().find(|f| f.name == name) {
+            file.contents = contents;
+        }
+        Ok(name)
+    }
+
+    /// Add the entry file with copies of the libraries it uses.
+    /// The paths of `.lib` and `.include` statements are rewritten to the bundled copies.
+    pub fn add_entry(&mut self, code: String, libs: IndexSet<PathBuf>) -> Result<(), CodeError> {
+        for lib in libs {
+            self.add_library(&lib)?;
+        }
+        let code = self.bundle_includes(&code, None)?;
+        let entry = self.entry.clone();
+        self.files.insert(0, File {name: entry, contents: code.into_bytes()});
+        Ok(())
+    }
+
+    /// Bundle the files of the `.lib` and `.include` statements in some code,
+    /// and rewrite their paths to the bundled names
+    fn bundle_includes(&mut self, code: &str, dir: Option<&Path>) -> Result<String, CodeError> {
+        let mut res = String::with_capacity(code.len());
+        for line in code.lines() {
+            match include_path(line, dir.is_some()) {
+                Some(range) => {
+                    let path = Path::new(&line[range.clone()]);
+                    let path = match dir {
+                        Some(dir) if path.is_relative() => dir.join(path),
+                        _ => path.into(),
+                    };
+                    let name = self.add_library(&path)?;
+                    res.push_str(&line[..range.start]);
+                    res.push_str(&name);
+                    res.push_str(&line[range.end..]);
+                }
+                None => res.push_str(line),
+            }
+            res.push('\n');
+        }
+        Ok(res)
+    }
+}
+
+/// Where the path of a SPICE `.lib`, `.include` or `.inc` statement is in a line, without quotes.
+/// Inside a library, a `.lib` with only a name starts a section.
+fn include_path(line: &str, library: bool) -> Option<std::ops::Range<usize>> {
+    let start = line.len() - line.trim_start().len();
+    let directive_end = start + line[start..].find(char::is_whitespace)?;
+    let directive = line[start..directive_end].to_lowercase();
+    if !matches!(directive.as_str(), ".lib" | ".include" | ".inc") {
+        return None;
+    }
+    let rest = &line[directive_end..];
+    let token_start = directive_end + rest.len() - rest.trim_start().len();
+    let token_end = line[token_start..].find(char::is_whitespace).map_or(line.len(), |end| token_start + end);
+    if library && directive == ".lib" && line[token_end..].trim().is_empty() {
+        return None;
+    }
+    let quotes = |c: char| c == '"' || c == '\'';
+    let token = &line[token_start..token_end];
+    let path = token.trim_start_matches(quotes);
+    let path_start = token_start + token.len() - path.len();
+    let path_end = path_start + path.trim_end_matches(quotes).len();
+    if path_start == path_end {
+        None
+    } else {
+        Some(path_start..path_end)
     }
 }
 
-pub trait Simulator: Copy {
+pub trait Simulator: Copy + 'static {
+    /// The name of the simulator, for messages
+    fn name(&self) -> &str;
     fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch>;
+    /// The simulator's name for a node given as a `/` separated path of instances, like `inv1/mid`
+    fn node_path(&self, path: &str) -> String { path.into() }
     fn synthesize_definition<S: Simulator>(&self, conf: &Configuration<S>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError>;
-    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &HashMap<String, String>, portmap: &HashMap<String, String>) -> Result<String, CodeError>;
+    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError>;
+    /// The declaration of an entity, for languages that need one before instantiating it
+    fn synthesize_declaration<S: Simulator>(&self, _conf: &Configuration<S>) -> Result<String, CodeError> {
+        Err(ErrorKind::Unsupported(format!("{} has no declarations", self.name())).into())
+    }
+}
+
+/// The syntax differences between the supported SPICE dialects
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SpiceFlavor {
+    Ngspice,
+    Xyce,
+}
+
+/// Subcircuit instances get an x prefix, which is left off the node itself
+fn spice_node_path(path: &str, separator: char) -> String {
+    let mut segments: Vec<String> = path.split('/').map(String::from).collect();
+    let last = segments.len() - 1;
+    for inst in &mut segments[..last] {
+        inst.insert(0, 'x');
+    }
+    segments.join(&separator.to_string())
 }
 
-fn spice_definition<S: Simulator>(sch: &Schematic, conf: &Configuration<S>) -> Result<IndexSet<Definition>, CodeError> {
+fn spice_definition<S: Simulator>(sch: &Schematic, conf: &Configuration<S>, flavor: SpiceFlavor) -> Result<IndexSet<Definition>, CodeError> {
     let mut defs = IndexSet::new();
     if sch.toplevel {
         let mut res = String::new();
-        res.push_str(&format!("* {}\n", conf.ent.name));
-        let mut sub_defs = IndexSet::new();
-        for (name, inst) in &sch.instances {
-            let subconf = conf.get_conf(name, inst);
-            // add to ordered set to avoid duplicates but maintain dependency order
-            sub_defs.extend(subconf.definition()?)
+        res.push_str(&format!("* {}\n", conf.ent.name()));
+        // make the generics of the testbench available to {expr} values
+        for g in &conf.ent.get()?.generic {
+            if let Some(val) = &g.default {
+                res.push_str(&format!(".param {}={}\n", g.name, val));
+            }
         }
-        for def in sub_defs {
+        for def in conf.instance_definitions(sch)? {
             match def {
                 Definition::Code(def) => res.push_str(&def),
-                Definition::Library(lib) => res.push_str(&format!(".lib {}", lib.to_str().ok_or(CodeError::CompileError(lib.to_string_lossy().into()))?)),
+                Definition::Library(lib) => {
+                    let path = lib.to_str().ok_or_else(|| ErrorKind::Unsupported(format!("non UTF-8 path {}", lib.display())))?;
+                    // Xyce only takes .lib with a section
+                    match flavor {
+                        SpiceFlavor::Ngspice => res.push_str(&format!(".lib {}", path)),
+                        SpiceFlavor::Xyce => res.push_str(&format!(".include {}", path)),
+                    }
+                }
                 Definition::Primitive => (),
             }
             res.push('\n');
         }
         for (name, inst) in &sch.instances {
             let subconf = conf.get_conf(name, inst);
-            res.push_str(&subconf.reference(name, &inst.genericmap, &inst.portmap)?);
+            res.push_str(&subconf.instance_reference(name, inst)?);
             res.push('\n');
         }
         res.push_str(".end\n");
         defs.insert(Definition::Code(res));
     } else {
-        for (name, inst) in &sch.instances {
-            let subconf = conf.get_conf(name, inst);
-            // add to ordered set to avoid duplicates but maintain dependency order
-            defs.extend(subconf.definition()?)
-        }
+        defs.extend(conf.instance_definitions(sch)?);
+        let ent = conf.ent.get()?;
         let mut res = String::new();
-        res.push_str(&format!(".subckt {}", conf.ent.name));
-        for port in &conf.ent.port {
-            res.push(' ');
-            res.push_str(port);
+        res.push_str(&format!(".subckt {}", ent.name));
+        // buses are expanded with the default generics
+        let defaults = ent.generic_defaults();
+        for port in &ent.port {
+            for node in port.nodes(&port.name, &defaults).map_err(ErrorKind::Bus)? {
+                res.push(' ');
+                res.push_str(&node);
+            }
+        }
+        // instances can refer to these as {expr}
+        if !ent.generic.is_empty() {
+            res.push_str(" params:");
+            for g in &ent.generic {
+                res.push(' ');
+                res.push_str(&g.name);
+                res.push('=');
+                // generics without default are always passed by the reference
+                let default = g.default.as_deref().unwrap_or("0").trim();
+                // a parameter value is a single token, unless it is an expression in braces
+                let braced = default.starts_with('{') && default.ends_with('}');
+                if default.is_empty() || (default.contains(char::is_whitespace) && !braced) {
+                    let message = format!("default {} = {:?} is no SPICE parameter value", g.name, default);
+                    return Err(ErrorKind::InvalidGeneric {entity: ent.name.clone(), message}.into());
+                }
+                res.push_str(default);
+            }
         }
         res.push('\n');
-        // TODO parameters
         for (name, inst) in &sch.instances {
             let subconf = conf.get_conf(name, inst);
-            res.push_str(&subconf.reference(name, &inst.genericmap, &inst.portmap)?);
+            res.push_str(&subconf.instance_reference(name, inst)?);
             res.push('\n');
         }
-        res.push_str(&format!(".ends {}", conf.ent.name));
+        res.push_str(&format!(".ends {}", conf.ent.name()));
         defs.insert(Definition::Code(res));
     }
     Ok(defs)
 }
-fn spice_reference<S: Simulator>(conf: &Configuration<S>, name: &str, genericmap: &HashMap<String, String>, portmap: &HashMap<String, String>) -> Result<String, CodeError> {
+fn spice_reference<S: Simulator>(conf: &Configuration<S>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>, flavor: SpiceFlavor) -> Result<String, CodeError> {
+    let ent = conf.ent.get()?;
     let mut res = String::with_capacity(64);
     res.push('x');
     res.push_str(name);
     // order matters
-    for p in &conf.ent.port {
+    let generics = ent.generic_values(genericmap)?;
+    let nodes = ent.port_nodes(portmap, &generics)?;
+    // a subcircuit has a fixed number of nodes
+    let defaults = ent.generic_defaults();
+    for p in &ent.port {
+        let expected = p.nodes(&p.name, &defaults).map_err(ErrorKind::Bus)?;
+        if expected.len() != nodes[&p.name].len() {
+            return Err(ErrorKind::Bus(format!("width of {} differs from the .subckt", p.name)).into());
+        }
+    }
+    for node in nodes.values().flatten() {
         res.push(' ');
-        res.push_str(portmap.get(p).ok_or(CodeError::CompileError(format!("no {} in {}", p, name)))?)
+        res.push_str(node);
     }
     res.push(' ');
-    res.push_str(&conf.ent.name);
-    for g in &conf.ent.generic {
+    res.push_str(&ent.name);
+    let mut params = false;
+    for g in &ent.generic {
+        // if not given, the default from the .subckt params applies
+        let val = match (genericmap.get(&g.name), &g.default) {
+            (Some(val), _) => val,
+            (None, Some(_)) => continue,
+            (None, None) => return Err(ErrorKind::MissingGeneric {entity: ent.name.clone(), generic: g.name.clone()}.into()),
+        };
+        if flavor == SpiceFlavor::Xyce && !params {
+            res.push_str(" params:");
+            params = true;
+        }
         res.push(' ');
-        res.push_str(g);
+        res.push_str(&g.name);
         res.push('=');
-        res.push_str(genericmap.get(g).ok_or(CodeError::CompileError(g.into()))?)
+        res.push_str(val);
     }
     Ok(res)
 }
 
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 pub struct Ngspice;
 
 impl Simulator for Ngspice {
+    fn name(&self) -> &str {
+        "ngspice"
+    }
     fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch> {
         arch.dialects.get("ngspice").or_else(|| arch.dialects.get("spice"))
     }
+    fn node_path(&self, path: &str) -> String {
+        spice_node_path(path, '.')
+    }
+    fn synthesize_definition<S: Simulator>(&self, conf: &Configuration<S>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+        spice_definition(ckt, conf, SpiceFlavor::Ngspice)
+    }
+    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        spice_reference(conf, name, genericmap, portmap, SpiceFlavor::Ngspice)
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct Xyce;
+
+impl Simulator for Xyce {
+    fn name(&self) -> &str {
+        "xyce"
+    }
+    fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch> {
+        arch.dialects.get("xyce").or_else(|| arch.dialects.get("spice"))
+    }
+    fn node_path(&self, path: &str) -> String {
+        spice_node_path(path, ':')
+    }
     fn synthesize_definition<S: Simulator>(&self, conf: &Configuration<S>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
-        spice_definition(ckt, conf)
+        spice_definition(ckt, conf, SpiceFlavor::Xyce)
     }
-    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &HashMap<String, String>, portmap: &HashMap<String, String>) -> Result<String, CodeError> {
-        spice_reference(conf, name, genericmap, portmap)
+    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        spice_reference(conf, name, genericmap, portmap, SpiceFlavor::Xyce)
     }
 }
 
-// pub struct Xyce;
-// pub struct Verilator;
-// pub struct GHDL;
+/// The analysis to run, for simulators that take it as part of the netlist
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Analysis {
+    /// The analysis statement without the dot, like `tran 1u 1m`
+    pub command: String,
+    /// Parameters to sweep, with their values
+    #[serde(default)]
+    pub step: IndexMap<String, Vec<String>>,
+    /// Node voltages to print, as `/` separated paths like `inv1/mid`
+    #[serde(default)]
+    pub print: Vec<String>,
+}
 
-// CXXRTL takes anything Yosys can read plus C++
-// pub struct CXXRTL;
+impl Xyce {
+    /// A complete netlist of a toplevel schematic, including the analysis.
+    /// The Xyce server runs the netlist as is, so it needs .print statements to return any results.
+    pub fn netlist<S: Simulator>(conf: &Configuration<S>, analysis: &Analysis) -> Result<String, CodeError> {
+        let mut res = match conf.definition()?.pop() {
+            Some(Definition::Code(code)) if code.ends_with(".end\n") => code[..code.len()-5].to_string(),
+            _ => return Err(ErrorKind::Unsupported(format!("{} is not a toplevel schematic", conf.ent.name())).into()),
+        };
+        res.push_str(&format!(".{}\n", analysis.command));
+        for (param, values) in &analysis.step {
+            res.push_str(&format!(".step {} list {}\n", param, values.join(" ")));
+        }
+        if !analysis.print.is_empty() {
+            let kind = analysis.command.split_whitespace().next().unwrap_or_default();
+            res.push_str(&format!(".print {}", kind));
+            for node in &analysis.print {
+                res.push_str(&format!(" v({})", conf.sim.node_path(node)));
+            }
+            res.push('\n');
+        }
+        res.push_str(".end\n");
+        Ok(res)
+    }
 
-// nMigen takes Python files
-// pub struct NMigen;
+    /// The netlist with analysis as `entry`, with copies of the libraries it uses
+    pub fn bundle<S: Simulator>(conf: &Configuration<S>, analysis: &Analysis, entry: &str) -> Result<Bundle, CodeError> {
+        let mut bundle = Bundle::new(entry);
+        bundle.add_entry(Xyce::netlist(conf, analysis)?, conf.libraries()?)?;
+        Ok(bundle)
+    }
+}
 
 impl<S: Simulator> Code for Configuration<S> {
     fn definition(&self) -> Result<IndexSet<Definition>, CodeError> {
-        match self.get_arch() {
-            Some(Arch::Code(arch)) => self.sim.get_dialect(arch).ok_or(CodeError::DialectError)?.definition(),
-            Some(Arch::Schematic(sch)) => self.sim.synthesize_definition(self, sch),
-            None => Err(CodeError::DialectError)
-        }
+        self.located(|| match self.get_arch()? {
+            Arch::Code(arch) => self.get_dialect(arch)?.definition(),
+            Arch::Schematic(sch) => self.sim.synthesize_definition(self, sch),
+        })
     }
-    fn reference(&self, name: &str, genericmap: &HashMap<String, String>, portmap: &HashMap<String, String>) -> Result<String, CodeError> {
-        match self.get_arch() {
-            Some(Arch::Code(arch)) => self.sim.get_dialect(arch).ok_or(CodeError::DialectError)?.reference(name, genericmap, portmap),
-            Some(Arch::Schematic(_sch)) => self.sim.synthesize_reference(self, name, genericmap, portmap),
-            None => Err(CodeError::DialectError)
-        }
+    fn declaration(&self) -> Result<String, CodeError> {
+        self.located(|| self.sim.synthesize_declaration(self))
+    }
+    fn reference(&self, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        self.located(|| {
+            // the instance may be bound to an entity with different names
+            let genericmap = &Self::bind(&self.genericmap, genericmap);
+            let portmap = &Self::bind(&self.portmap, portmap);
+            // validate before emitting anything, templates get the defaults filled in
+            let ent = self.ent.get()?;
+            let generics = ent.generic_values(genericmap)?;
+            match self.get_arch()? {
+                Arch::Code(arch) => {
+                    // buses are passed to templates as a list of scalar nodes
+                    let mut ports = portmap.clone();
+                    for p in ent.port.iter().filter(|p| p.range.is_some()) {
+                        if let Some(net) = portmap.get(&p.name) {
+                            let nodes = p.nodes(net, &generics).map_err(ErrorKind::Bus)?;
+                            ports.insert(p.name.clone(), nodes.join(" "));
+                        }
+                    }
+                    self.get_dialect(arch)?.reference(name, &generics, &ports)
+                }
+                // subcircuits get their defaults from the .subckt params
+                Arch::Schematic(_sch) => self.sim.synthesize_reference(self, name, genericmap, portmap),
+            }
+        })
     }
 }
 
@@ -304,13 +1488,13 @@ mod tests {
         };
         let mut spicemos = CodeDialectArch::new();
         spicemos.dialects.insert("spice".into(), code);
-        let mut arches = HashMap::new();
+        let mut arches = IndexMap::new();
         arches.insert("rtl".into(), Arch::Code(spicemos));
 
         let pmos = Rc::from(Entity {
             name: "pmos".into(),
             symbol: Symbol {},
-            generic: vec!["w".into(), "l".into()],
+            generic: vec![Generic::new("w", "1u"), Generic::new("l", "1u")],
             port: vec!["g".into(), "d".into(), "s".into(), "b".into()],
             archs: arches,
         });
@@ -321,25 +1505,26 @@ mod tests {
         };
         let mut spicemos = CodeDialectArch::new();
         spicemos.dialects.insert("spice".into(), code);
-        let mut arches = HashMap::new();
+        let mut arches = IndexMap::new();
         arches.insert("rtl".into(), Arch::Code(spicemos));
 
         let nmos = Rc::from(Entity {
             name: "nmos".into(),
             symbol: Symbol {},
-            generic: vec!["w".into(), "l".into()],
+            generic: vec![Generic::new("w", "1u"), Generic::new("l", "1u")],
             port: vec!["g".into(), "d".into(), "s".into(), "b".into()],
             archs: arches,
         });
 
         let mut cir = Schematic {
             toplevel: true,
-            instances: HashMap::new(),
+            level: None,
+            instances: IndexMap::new(),
         };
         cir.instances.insert(
                 "pmos1".into(),
                 Instance {
-                    genericmap: HashMap::new(),
+                    genericmap: IndexMap::new(),
                     portmap: collection!{
                         "g".into() => "in".into(),
                         "d".into() => "mid".into(),
@@ -348,12 +1533,12 @@ mod tests {
                     },
                     x: 0,
                     y: 0,
-                    entity: pmos.clone(),
+                    entity: pmos.clone().into(),
                 });
         cir.instances.insert(
                 "nmos1".into(),
                 Instance {
-                    genericmap: HashMap::new(),
+                    genericmap: IndexMap::new(),
                     portmap: collection!{
                         "g".into() => "in".into(),
                         "d".into() => "mid".into(),
@@ -362,12 +1547,12 @@ mod tests {
                     },
                     x: 0,
                     y: 0,
-                    entity: nmos.clone(),
+                    entity: nmos.clone().into(),
                 });
         cir.instances.insert(
                 "pmos2".into(),
                 Instance {
-                    genericmap: HashMap::new(),
+                    genericmap: IndexMap::new(),
                     portmap: collection!{
                         "g".into() => "mid".into(),
                         "d".into() => "out".into(),
@@ -376,13 +1561,13 @@ mod tests {
                     },
                     x: 0,
                     y: 0,
-                    entity: pmos.clone(),
+                    entity: pmos.clone().into(),
                 });
 
         cir.instances.insert(
                 "nmos2".into(),
                 Instance {
-                    genericmap: HashMap::new(),
+                    genericmap: IndexMap::new(),
                     portmap: collection!{
                         "g".into() => "mid".into(),
                         "d".into() => "out".into(),
@@ -391,7 +1576,7 @@ mod tests {
                     },
                     x: 0,
                     y: 0,
-                    entity: nmos.clone(),
+                    entity: nmos.clone().into(),
                 });
         let top = Entity {
             name: "buf".into(),
@@ -401,16 +1586,21 @@ mod tests {
             archs: collection!{"default".into() => Arch::Schematic(cir)},
         };
         let conf = Configuration {
-            sim: Ngspice,
-            ent: Rc::from(top),
             arch: Some("default".into()),
-            for_inst: RefCell::from(HashMap::new()),
-            all: HashMap::new(),
+            ..Configuration::new(Ngspice, Rc::from(top).into())
         };
-        if let Definition::Code(code) = &conf.definition().unwrap()[0] {
-            println!("{}", code);
+        let netlist = "* buf\n\
+            .model PMOS\n\
+            .model NMOS\n\
+            mpmos1 mid in vdd vdd PMOS W=1u L=1u\n\
+            mnmos1 mid in vss vss NMOS W=1u L=1u\n\
+            mpmos2 out mid vdd vdd PMOS W=1u L=1u\n\
+            mnmos2 out mid vss vss NMOS W=1u L=1u\n\
+            .end\n";
+        // instances and definitions are emitted in insertion order, every time
+        for _ in 0..3 {
+            assert_eq!(conf.definition().unwrap(), indexset!{Definition::Code(netlist.into())});
         }
-        // assert_eq!(Ngspice(&cir).definition().unwrap(), "");
     }
 
     #[test]
@@ -418,59 +1608,584 @@ mod tests {
         let code = CodeArch {
             reference: "{{generic.name}}, {{port.platitude}}".to_string(),
             definition: Definition::Code("hello".into())};
-        let mut generics = HashMap::new();
+        let mut generics = IndexMap::new();
         generics.insert("name".to_string(), "world".to_string());
-        let mut ports = HashMap::new();
+        let mut ports = IndexMap::new();
         ports.insert("platitude".to_string(), "whatsup".to_string());
         assert_eq!(code.definition().unwrap(), indexset!{Definition::Code("hello".into())});
         assert_eq!(code.reference("foo", &generics, &ports).unwrap(), "world, whatsup");
     }
 
-    // #[test]
-    // fn spice_arch() {
-    //     let mut spice = CodeDialectArch::new();
-    //     spice.dialects.insert("spice".into(), CodeArch {definition: "this is spice".into(), reference: "spice ref".into()});
-    //     spice.dialects.insert("ngspice".into(), CodeArch {definition: "this is ngspice".into(), reference: "ngspice ref".into()});
-    //     assert_eq!(Ngspice(&spice).definition().unwrap(), "this is ngspice");
-    //     assert_eq!(Xyce(&spice).definition().unwrap(), "this is spice");
-    //     assert_eq!(Ngspice(&spice).reference("foo", &HashMap::new(), &HashMap::new()).unwrap(), "ngspice ref");
-    //     assert_eq!(Xyce(&spice).reference("bar", &HashMap::new(), &HashMap::new()).unwrap(), "spice ref");
-
-
-    //     let mut spice = CodeDialectArch::new();
-    //     spice.dialects.insert("spice".into(), CodeArch {definition: "this is spice".into(), reference: "spice ref".into()});
-    //     spice.dialects.insert("xyce".into(), CodeArch {definition: "this is xyce".into(), reference: "xyce ref".into()});
-    //     assert_eq!(Ngspice(&spice).definition().unwrap(), "this is spice");
-    //     assert_eq!(Xyce(&spice).definition().unwrap(), "this is xyce");
-    //     assert_eq!(Ngspice(&spice).reference("foo", &HashMap::new(), &HashMap::new()).unwrap(), "spice ref");
-    //     assert_eq!(Xyce(&spice).reference("bar", &HashMap::new(), &HashMap::new()).unwrap(), "xyce ref");
-    // }
-
-    // #[test]
-    // fn verilator_arch() {
-    //     let mut verilog = CodeDialectArch::new();
-    //     verilog.dialects.insert("verilog".into(), CodeArch {definition: "this is verilog".into(), reference: "verilog ref".into()});
-    //     assert_eq!(Verilator(&verilog).definition().unwrap(), "this is verilog");
-    //     assert_eq!(Verilator(&verilog).reference("foo", &HashMap::new(), &HashMap::new()).unwrap(), "verilog ref");
-
-    //     verilog.dialects.insert("verilator".into(), CodeArch {definition: "this is verilator".into(), reference: "verilator ref".into()});
-    //     assert_eq!(Verilator(&verilog).definition().unwrap(), "this is verilator");
-    //     assert_eq!(Verilator(&verilog).reference("foo", &HashMap::new(), &HashMap::new()).unwrap(), "verilator ref");
-    //     assert_eq!(Ngspice(&verilog).definition().is_err(), true);
-    // }
-
-    // #[test]
-    // fn ghdl_arch() {
-    //     let mut vhdl = CodeDialectArch::new();
-    //     vhdl.dialects.insert("vhdl".into(), CodeArch {definition: "this is vhdl".into(), reference: "vhdl ref".into()});
-    //     assert_eq!(GHDL(&vhdl).definition().unwrap(), "this is vhdl");
-    //     assert_eq!(GHDL(&vhdl).reference("foo", &HashMap::new(), &HashMap::new()).unwrap(), "vhdl ref");
-
-    //     vhdl.dialects.insert("ghdl".into(), CodeArch {definition: "this is ghdl".into(), reference: "ghdl ref".into()});
-    //     assert_eq!(GHDL(&vhdl).definition().unwrap(), "this is ghdl");
-    //     assert_eq!(GHDL(&vhdl).reference("foo", &HashMap::new(), &HashMap::new()).unwrap(), "ghdl ref");
-    //     assert_eq!(Xyce(&vhdl).definition().is_err(), true);
-    // }
+    #[test]
+    fn spice_arch() {
+        let arch = |dialects: &[&str]| CodeDialectArch {level: None, dialects: dialects.iter().map(|&d| (d.to_string(), CodeArch {
+            definition: Definition::Code(format!("this is {}", d)),
+            reference: format!("{} ref", d),
+        })).collect()};
+        let def = |arch: Option<&CodeArch>| arch.unwrap().definition.clone();
+        let spice = arch(&["spice", "ngspice"]);
+        assert_eq!(def(Ngspice.get_dialect(&spice)), Definition::Code("this is ngspice".into()));
+        assert_eq!(def(Xyce.get_dialect(&spice)), Definition::Code("this is spice".into()));
+        let spice = arch(&["spice", "xyce"]);
+        assert_eq!(def(Ngspice.get_dialect(&spice)), Definition::Code("this is spice".into()));
+        assert_eq!(def(Xyce.get_dialect(&spice)), Definition::Code("this is xyce".into()));
+        assert_eq!(Xyce.node_path("buf/inv1/mid"), "xbuf:xinv1:mid");
+        assert_eq!(Ngspice.node_path("inv1/mid"), "xinv1.mid");
+    }
+
+    #[test]
+    fn verilator_arch() {
+        let mut verilog = CodeDialectArch::new();
+        verilog.dialects.insert("verilog".into(), CodeArch {definition: Definition::Code("this is verilog".into()), reference: "verilog ref".into()});
+        assert_eq!(Verilator.get_dialect(&verilog).unwrap().reference("foo", &IndexMap::new(), &IndexMap::new()).unwrap(), "verilog ref");
+        verilog.dialects.insert("verilator".into(), CodeArch {definition: Definition::Code("this is verilator".into()), reference: "verilator ref".into()});
+        assert_eq!(Verilator.get_dialect(&verilog).unwrap().reference("foo", &IndexMap::new(), &IndexMap::new()).unwrap(), "verilator ref");
+        assert!(Ngspice.get_dialect(&verilog).is_none());
+    }
+
+    #[test]
+    fn ghdl_arch() {
+        let mut vhdl = CodeDialectArch::new();
+        vhdl.dialects.insert("vhdl".into(), CodeArch {definition: Definition::Code("this is vhdl".into()), reference: "vhdl ref".into()});
+        assert_eq!(Ghdl.get_dialect(&vhdl).unwrap().reference("foo", &IndexMap::new(), &IndexMap::new()).unwrap(), "vhdl ref");
+        vhdl.dialects.insert("ghdl".into(), CodeArch {definition: Definition::Code("this is ghdl".into()), reference: "ghdl ref".into()});
+        assert_eq!(Ghdl.get_dialect(&vhdl).unwrap().reference("foo", &IndexMap::new(), &IndexMap::new()).unwrap(), "ghdl ref");
+        assert!(Xyce.get_dialect(&vhdl).is_none());
+    }
+
+    #[test]
+    fn serde_library() {
+        let lib: Library = toml::from_str(&std::fs::read_to_string("data/inverter.toml").unwrap()).unwrap();
+        // dependencies are inserted before the entities using them
+        let names: Vec<&str> = lib.entities().map(|ent| ent.name.as_str()).collect();
+        assert_eq!(names, vec!["pmos", "nmos", "inverter"]);
+
+        let json = serde_json::to_string(&lib).unwrap();
+        let lib2: Library = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_value(&lib).unwrap(), serde_json::to_value(&lib2).unwrap());
+        let text = toml::to_string(&lib2).unwrap();
+        let lib3: Library = toml::from_str(&text).unwrap();
+        assert_eq!(toml::Value::try_from(&lib).unwrap(), toml::Value::try_from(&lib3).unwrap());
+
+        let mut conf: Configuration<Ngspice> = serde_json::from_str(r#"{
+            "ent": "inverter",
+            "for_inst": {"nmos": {"ent": "nmos", "arch": "rtl"}}
+        }"#).unwrap();
+        assert!(conf.definition().is_err());
+        conf.resolve(&lib3).unwrap();
+        if let Definition::Code(code) = &conf.definition().unwrap()[2] {
+            assert!(code.starts_with(".subckt inverter vdd gnd in out params: w=1u\n"));
+            assert!(code.contains("mnmos out in gnd gnd NMOS W={w} L=1u\n"));
+        } else {
+            panic!("expected subckt");
+        }
+        let json = serde_json::to_value(&conf).unwrap();
+        assert_eq!(json["for_inst"]["nmos"]["arch"], "rtl");
+
+        let missing: Result<Library, _> = serde_json::from_str(r#"{"entity": [{
+            "name": "top", "generic": [], "port": [],
+            "archs": {"default": {"schematic": {"toplevel": true, "instances": {
+                "x1": {"portmap": {}, "genericmap": {}, "x": 0, "y": 0, "entity": "nope"}
+            }}}}
+        }]}"#);
+        assert!(missing.is_err());
+    }
+
+    /// An entity without a symbol
+    pub(crate) fn entity(name: &str, generic: Vec<Generic>, port: Vec<Port>, archs: IndexMap<String, Arch>) -> Entity {
+        Entity {name: name.into(), symbol: Symbol {}, generic, port, archs}
+    }
+
+    /// An instance of an entity by name, to be resolved when its schematic is inserted in a library
+    pub(crate) fn instance(entity: &str, genericmap: IndexMap<String, String>, portmap: IndexMap<String, String>) -> Instance {
+        Instance {genericmap, portmap, x: 0, y: 0, entity: EntityRef::new(entity)}
+    }
+
+    pub(crate) fn schematic(toplevel: bool, instances: IndexMap<String, Instance>) -> Arch {
+        Arch::Schematic(Schematic {toplevel, level: None, instances})
+    }
+
+    /// An arch with code in one dialect
+    pub(crate) fn code(dialect: &str, definition: Definition, reference: &str) -> Arch {
+        Arch::Code(CodeDialectArch {level: None, dialects: collection!{dialect.into() => CodeArch {definition, reference: reference.into()}}})
+    }
+
+    /// A logic buffer `repeater` of two inverters `inv` in a testbench `tb`, for the HDL backends.
+    /// The first inverter reads `select` of the input `a` of the buffer and gets the `delay`,
+    /// and the testbench connects `a` to `nets`.
+    pub(crate) fn repeater_tb(inv: Arch, generic: Vec<Generic>, a: Port, select: &str, delay: &str, nets: &str) -> (Library, Rc<Entity>) {
+        let mut lib = Library::new();
+        let logic = |name: &str, direction| Port::new(name, direction, Nature::Logic);
+        let delay_generic = Generic {name: "delay".into(), kind: GenericKind::Quantity, default: Some("1n".into()), ..Generic::default()};
+        lib.insert(entity("inv", vec![delay_generic], vec![logic("a", Direction::In), logic("y", Direction::Out)], collection!{"rtl".into() => inv})).unwrap();
+        let inv = |genericmap, a: &str, y: &str| instance("inv", genericmap, collection!{"a".into() => a.into(), "y".into() => y.into()});
+        lib.insert(entity("repeater", generic, vec![a, logic("y", Direction::Out)], collection!{"structural".into() => schematic(false, collection!{
+            "inv1".into() => inv(collection!{"delay".into() => delay.into()}, select, "mid"),
+            "inv2".into() => inv(IndexMap::new(), "mid", "y"),
+        })})).unwrap();
+        let b1 = instance("repeater", IndexMap::new(), collection!{"a".into() => nets.into(), "y".into() => "z".into()});
+        let tb = lib.insert(entity("tb", Vec::new(), Vec::new(), collection!{"test".into() => schematic(true, collection!{"b1".into() => b1})})).unwrap();
+        (lib, tb)
+    }
+
+    /// Two inverters from data/inverter.toml, the first with a width from the testbench
+    pub(crate) fn inverter_tb() -> Rc<Entity> {
+        let mut lib: Library = toml::from_str(&std::fs::read_to_string("data/inverter.toml").unwrap()).unwrap();
+        let mut cir = Schematic {
+            toplevel: true,
+            level: None,
+            instances: IndexMap::new(),
+        };
+        cir.instances.insert("inv1".into(), Instance {
+            genericmap: collection!{"w".into() => "{wn}".into()},
+            portmap: collection!{
+                "vdd".into() => "vdd".into(),
+                "gnd".into() => "0".into(),
+                "in".into() => "in".into(),
+                "out".into() => "mid".into(),
+            },
+            x: 0,
+            y: 0,
+            entity: EntityRef::new("inverter"),
+        });
+        cir.instances.insert("inv2".into(), Instance {
+            genericmap: IndexMap::new(),
+            portmap: collection!{
+                "vdd".into() => "vdd".into(),
+                "gnd".into() => "0".into(),
+                "in".into() => "mid".into(),
+                "out".into() => "out".into(),
+            },
+            x: 0,
+            y: 0,
+            entity: EntityRef::new("inverter"),
+        });
+        lib.insert(Entity {
+            name: "tb".into(),
+            symbol: Symbol {},
+            generic: vec![Generic::new("wn", "2u")],
+            port: Vec::new(),
+            archs: collection!{"default".into() => Arch::Schematic(cir)},
+        }).unwrap()
+    }
+
+    #[test]
+    fn subckt_params() {
+        let tb = inverter_tb();
+        let conf = Configuration::new(Ngspice, tb.into());
+        let netlist = "* tb\n\
+            .param wn=2u\n\
+            .model PMOS PMOS\n\
+            .model NMOS NMOS\n\
+            .subckt inverter vdd gnd in out params: w=1u\n\
+            mpmos out in vdd vdd PMOS W={2*w} L=1u\n\
+            mnmos out in gnd gnd NMOS W={w} L=1u\n\
+            .ends inverter\n\
+            xinv1 vdd 0 in mid inverter w={wn}\n\
+            xinv2 vdd 0 mid out inverter\n\
+            .end\n";
+        assert_eq!(conf.definition().unwrap(), indexset!{Definition::Code(netlist.into())});
+
+        let sub = |default: &str| Configuration::new(Ngspice, Rc::from(Entity {
+            name: "sub".into(),
+            symbol: Symbol {},
+            generic: vec![Generic::new("w", default)],
+            port: Vec::new(),
+            archs: collection!{"default".into() => Arch::Schematic(Schematic {toplevel: false, level: None, instances: IndexMap::new()})},
+        }).into()).definition();
+        assert!(sub("{2 * 1u}").is_ok());
+        for default in &["", "1 u"] {
+            assert!(matches!(sub(default).map_err(|e| e.kind), Err(ErrorKind::InvalidGeneric {..})));
+        }
+    }
+
+    #[test]
+    fn error_context() {
+        let tb = inverter_tb();
+        let inverter = match &tb.archs["default"] {
+            Arch::Schematic(sch) => sch.instances["inv2"].entity.clone(),
+            _ => unreachable!(),
+        };
+        let mut conf = Configuration::new(Ngspice, tb.into());
+        conf.for_inst.insert("inv2".into(), Configuration {
+            arch: Some("rtl".into()),
+            ..Configuration::new(Ngspice, inverter)
+        });
+        let err = conf.definition().unwrap_err();
+        assert!(matches!(&err.kind, ErrorKind::NoArch {entity, arch} if entity == "inverter" && arch == "rtl"));
+        assert_eq!(err.path(), "tb/inv2");
+        assert_eq!(err.to_string(), "tb/inv2 inverter(rtl) for ngspice: no architecture rtl of inverter");
+
+        // digital simulators can not use the transistors of the inverter
+        let err = Configuration::new(Ghdl, inverter_tb().into()).definition().unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::NoDialect {..}));
+        assert_eq!(err.path(), "tb/inv1/pmos");
+        assert!(std::error::Error::source(&err).is_none());
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut lib: Library = toml::from_str(&std::fs::read_to_string("data/inverter.toml").unwrap()).unwrap();
+        let tb = lib.insert(entity("tb", Vec::new(), Vec::new(), collection!{"default".into() => schematic(true, collection!{
+            "inv1".into() => instance("inverter", IndexMap::new(), collection!{
+                "vdd".into() => "vdd".into(),
+                "gnd".into() => "0".into(),
+                "out".into() => "mid".into(),
+                "bulk".into() => "0".into(),
+            }),
+            "inv2".into() => instance("inverter", collection!{"l".into() => "1u".into()}, collection!{
+                "vdd".into() => "vdd".into(),
+                "gnd".into() => "0".into(),
+                "in".into() => "mid".into(),
+                "out".into() => "out".into(),
+            }),
+        })})).unwrap();
+        let conf = Configuration::new(Ngspice, tb.clone().into());
+        let mut diagnostics = Vec::new();
+        conf.diagnose(&mut diagnostics);
+        let found: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(found, vec![
+            "error: tb/inv1 inverter(default) for ngspice: port in of inverter is not connected",
+            "error: tb/inv1 inverter(default) for ngspice: no port bulk on inverter",
+            "error: tb/inv2 inverter(default) for ngspice: no generic l on inverter",
+        ]);
+        // failing fast only gives the first
+        assert!(matches!(conf.definition().unwrap_err().kind, ErrorKind::MissingPort {..}));
+
+        // every primitive without a dialect
+        let mut diagnostics = Vec::new();
+        Configuration::new(Ghdl, tb.into()).diagnose(&mut diagnostics);
+        let paths: Vec<String> = diagnostics.iter().filter(|d| matches!(d.error.kind, ErrorKind::NoDialect {..})).map(|d| d.error.path()).collect();
+        assert_eq!(paths, vec!["tb/inv1/pmos", "tb/inv1/nmos", "tb/inv2/pmos", "tb/inv2/nmos"]);
+
+        let mut diagnostics = Vec::new();
+        Configuration::new(Ngspice, inverter_tb().into()).diagnose(&mut diagnostics);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn xyce_netlist() {
+        let conf = Configuration::new(Xyce, inverter_tb().into());
+        let analysis = Analysis {
+            command: "tran 1u 1m".into(),
+            step: collection!{"wn".into() => vec!["1u".into(), "2u".into()]},
+            print: vec!["inv1/out".into(), "out".into()],
+        };
+        let netlist = "* tb\n\
+            .param wn=2u\n\
+            .model PMOS PMOS\n\
+            .model NMOS NMOS\n\
+            .subckt inverter vdd gnd in out params: w=1u\n\
+            mpmos out in vdd vdd PMOS W={2*w} L=1u\n\
+            mnmos out in gnd gnd NMOS W={w} L=1u\n\
+            .ends inverter\n\
+            xinv1 vdd 0 in mid inverter params: w={wn}\n\
+            xinv2 vdd 0 mid out inverter\n\
+            .tran 1u 1m\n\
+            .step wn list 1u 2u\n\
+            .print tran v(xinv1:out) v(out)\n\
+            .end\n";
+        assert_eq!(Xyce::netlist(&conf, &analysis).unwrap(), netlist);
+    }
+
+    #[test]
+    fn typed_generics() {
+        let res = Entity {
+            name: "resistor".into(),
+            symbol: Symbol {},
+            generic: vec![
+                Generic {name: "r".into(), kind: GenericKind::Quantity, unit: Some("Ohm".into()), default: Some("1k".into()), min: Some(0.0), ..Generic::default()},
+                Generic {name: "m".into(), kind: GenericKind::Integer, default: Some("1".into()), min: Some(1.0), ..Generic::default()},
+            ],
+            port: vec!["p".into(), "n".into()],
+            archs: IndexMap::new(),
+        };
+        let values = res.generic_values(&collection!{"r".into() => "4.7kOhm".into()}).unwrap();
+        assert_eq!(values["r"], "4.7kOhm");
+        assert_eq!(values["m"], "1");
+        assert!(res.generic_values(&collection!{"r".into() => "{2*r}".into()}).is_ok());
+        assert!(res.generic_values(&collection!{"r".into() => "-1".into()}).is_err());
+        assert!(res.generic_values(&collection!{"r".into() => "big".into()}).is_err());
+        assert!(res.generic_values(&collection!{"m".into() => "1.5".into()}).is_err());
+        assert!(res.generic_values(&collection!{"c".into() => "1".into()}).is_err());
+
+        let mut lib = Library::new();
+        assert!(lib.insert(Entity {
+            name: "bad".into(),
+            symbol: Symbol {},
+            generic: vec![Generic {name: "n".into(), kind: GenericKind::Integer, default: Some("x".into()), ..Generic::default()}],
+            port: Vec::new(),
+            archs: IndexMap::new(),
+        }).is_err());
+    }
+
+    #[test]
+    fn literals() {
+        let cap = Generic {name: "c".into(), kind: GenericKind::Quantity, unit: Some("F".into()), ..Generic::default()};
+        let mode = Generic {name: "mode".into(), kind: GenericKind::String, ..Generic::default()};
+        let n = Generic {name: "n".into(), kind: GenericKind::Integer, ..Generic::default()};
+        let ent = entity("cell", vec![cap.clone(), mode.clone(), n.clone()], Vec::new(), IndexMap::new());
+        assert_eq!(literal(&ent, &cap, "1.5uF").unwrap(), Literal::Real(1.5e-6));
+        assert_eq!(literal(&ent, &cap, "{2*delay}").unwrap(), Literal::Expr("2*delay"));
+        assert!(matches!(literal(&ent, &cap, "1.5uV").map_err(|e| e.kind), Err(ErrorKind::InvalidGeneric {..})));
+        assert_eq!(literal(&ent, &mode, "fast").unwrap(), Literal::String("fast"));
+        assert_eq!(literal(&ent, &mode, "\"fast\"").unwrap(), Literal::String("fast"));
+        assert_eq!(literal(&ent, &n, " 4").unwrap(), Literal::Other("4"));
+    }
+
+    #[test]
+    fn bus_ports() {
+        let mut lib = Library::new();
+        let and = CodeArch {
+            reference: "a{{name}} [{{port.a}}] {{port.y}} and".into(),
+            definition: Definition::Primitive,
+        };
+        lib.insert(Entity {
+            name: "andn".into(),
+            symbol: Symbol {},
+            generic: vec![Generic {name: "n".into(), kind: GenericKind::Integer, default: Some("2".into()), ..Generic::default()}],
+            port: vec![
+                Port {range: Some(BusRange::new("n-1", RangeDirection::Downto, "0")), ..Port::new("a", Direction::In, Nature::Logic)},
+                Port::new("y", Direction::Out, Nature::Logic),
+            ],
+            archs: collection!{"xspice".into() => Arch::Code(CodeDialectArch {level: None, dialects: collection!{"ngspice".into() => and}})},
+        }).unwrap();
+        let mut cir = Schematic {
+            toplevel: false,
+            level: None,
+            instances: IndexMap::new(),
+        };
+        cir.instances.insert("g1".into(), Instance {
+            genericmap: collection!{"n".into() => "3".into()},
+            portmap: collection!{"a".into() => "d".into(), "y".into() => "y".into()},
+            x: 0,
+            y: 0,
+            entity: EntityRef::new("andn"),
+        });
+        let and3 = lib.insert(Entity {
+            name: "and3".into(),
+            symbol: Symbol {},
+            generic: Vec::new(),
+            port: vec![
+                Port {range: Some(BusRange::new("0", RangeDirection::To, "2")), ..Port::new("d", Direction::In, Nature::Logic)},
+                Port::new("y", Direction::Out, Nature::Logic),
+            ],
+            archs: collection!{"default".into() => Arch::Schematic(cir)},
+        }).unwrap();
+        let conf = Configuration::new(Ngspice, and3.into());
+        assert_eq!(conf.definition().unwrap(), indexset!{Definition::Primitive, Definition::Code(".subckt and3 d_0 d_1 d_2 y\nag1 [d_2 d_1 d_0] y and\n.ends and3".into())});
+        assert_eq!(conf.reference("u1", &IndexMap::new(), &collection!{"d".into() => "a b c".into(), "y".into() => "out".into()}).unwrap(), "xu1 a b c out and3");
+        assert!(conf.reference("u1", &IndexMap::new(), &collection!{"d".into() => "a b".into(), "y".into() => "out".into()}).is_err());
+    }
+
+    #[test]
+    fn substitute() {
+        let mut lib = Library::new();
+        let model = CodeArch {
+            reference: "x{{name}} {{port.inp}} {{port.inn}} {{port.out}} {{port.vcc}} opa{{generic.gain}}".into(),
+            definition: Definition::Library("vendor.lib".into()),
+        };
+        let vendor = lib.insert(Entity {
+            name: "vendor_opamp".into(),
+            symbol: Symbol {},
+            generic: vec![Generic::new("gain", "100")],
+            port: vec!["inp".into(), "inn".into(), "out".into(), "vcc".into()],
+            archs: collection!{"model".into() => Arch::Code(CodeDialectArch {level: None, dialects: collection!{"spice".into() => model}})},
+        }).unwrap();
+        lib.insert(Entity {
+            name: "opamp".into(),
+            symbol: Symbol {},
+            generic: vec![Generic::new("a", "50")],
+            port: vec!["p".into(), "n".into(), "o".into()],
+            archs: IndexMap::new(),
+        }).unwrap();
+        let opamp = |a: &str| Instance {
+            genericmap: if a.is_empty() { IndexMap::new() } else { collection!{"a".into() => a.into()} },
+            portmap: collection!{"p".into() => "in".into(), "n".into() => "fb".into(), "o".into() => "out".into()},
+            x: 0,
+            y: 0,
+            entity: EntityRef::new("opamp"),
+        };
+        let tb = lib.insert(Entity {
+            name: "tb".into(),
+            symbol: Symbol {},
+            generic: Vec::new(),
+            port: Vec::new(),
+            archs: collection!{"default".into() => Arch::Schematic(Schematic {
+                toplevel: true,
+                level: None,
+                instances: collection!{"u1".into() => opamp("10"), "u2".into() => opamp("")},
+            })},
+        }).unwrap();
+        let binding = || Configuration {
+            portmap: collection!{"inp".into() => "p".into(), "inn".into() => "n".into(), "out".into() => "o".into(), "vcc".into() => "\"vdd\"".into()},
+            genericmap: collection!{"gain".into() => "a".into()},
+            ..Configuration::new(Ngspice, vendor.clone().into())
+        };
+        let conf = Configuration {
+            for_inst: collection!{"u1".into() => binding(), "u2".into() => binding()},
+            ..Configuration::new(Ngspice, tb.into())
+        };
+        let netlist = "* tb\n\
+            .lib vendor.lib\n\
+            xu1 in fb out vdd opa10\n\
+            xu2 in fb out vdd opa50\n\
+            .end\n";
+        assert_eq!(conf.definition().unwrap(), indexset!{Definition::Code(netlist.into())});
+
+        // an actual that is neither a port of the instance nor quoted is a typo
+        let mut typo = binding();
+        typo.portmap.insert("vcc".into(), "vdd".into());
+        let conf = Configuration {
+            for_inst: collection!{"u1".into() => binding(), "u2".into() => typo},
+            ..Configuration::new(Ngspice, conf.ent.clone())
+        };
+        let err = conf.definition().unwrap_err();
+        assert!(matches!(&err.kind, ErrorKind::UnknownPort {entity, port} if entity == "opamp" && port == "vdd"));
+        assert_eq!(err.path(), "tb/u2");
+    }
+
+    #[test]
+    fn bundle() {
+        let dir = std::env::temp_dir().join(format!("amscircuit_bundle_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("other")).unwrap();
+        std::fs::write(dir.join("models.lib"), ".model NMOS NMOS\n.include 'other/models.lib'\n.lib corners.lib tt\n").unwrap();
+        std::fs::write(dir.join("corners.lib"), ".lib tt\n.lib models.lib nmos\n.endl tt\n").unwrap();
+        std::fs::write(dir.join("other/models.lib"), ".model PMOS PMOS\n").unwrap();
+        let mut lib = Library::new();
+        let mos = |name: &str, path: PathBuf| {
+            let reference = format!("m{{{{name}}}} {{{{port.d}}}} {{{{port.g}}}} {{{{port.s}}}} {{{{port.s}}}} {}", name.to_uppercase());
+            entity(name, Vec::new(), vec!["d".into(), "g".into(), "s".into()], collection!{"model".into() => code("spice", Definition::Library(path), &reference)})
+        };
+        lib.insert(mos("nmos", dir.join("models.lib"))).unwrap();
+        lib.insert(mos("pmos", dir.join("other/models.lib"))).unwrap();
+        let inst = |entity: &str, d: &str| instance(entity, IndexMap::new(), collection!{"d".into() => d.into(), "g".into() => "in".into(), "s".into() => "0".into()});
+        let tb = lib.insert(entity("tb", Vec::new(), Vec::new(), collection!{"default".into() => schematic(true, collection!{
+            "n1".into() => inst("nmos", "a"),
+            "p1".into() => inst("pmos", "b"),
+            "n2".into() => inst("nmos", "c"),
+        })})).unwrap();
+        let conf = Configuration::new(Ngspice, tb.into());
+        let bundle = conf.bundle("tb.sp").unwrap();
+        let names: Vec<&str> = bundle.files.iter().map(|f| f.name.as_str()).collect();
+        // the library of the pmos is included by the one of the nmos already
+        assert_eq!(names, vec!["tb.sp", "models.lib", "models_1.lib", "corners.lib"]);
+        assert_eq!(bundle.entry, "tb.sp");
+        assert_eq!(bundle.files[0].contents, b"* tb\n\
+            .lib models.lib\n\
+            .lib models_1.lib\n\
+            mn1 a in 0 0 NMOS\n\
+            mp1 b in 0 0 PMOS\n\
+            mn2 c in 0 0 NMOS\n\
+            .end\n");
+        assert_eq!(bundle.get("models_1.lib").unwrap().contents, b".model PMOS PMOS\n");
+        assert_eq!(bundle.get("models.lib").unwrap().contents, b".model NMOS NMOS\n.include 'models_1.lib'\n.lib corners.lib tt\n");
+        // a .lib with only a name starts a section, and libraries that include each other are bundled once
+        assert_eq!(bundle.get("corners.lib").unwrap().contents, b".lib tt\n.lib models.lib nmos\n.endl tt\n");
+        assert_eq!(include_path("  .LIB \"models.lib\" tt", false), Some(8..18));
+        assert_eq!(include_path(".libfile x", false), None);
+    }
+
+    #[test]
+    fn hierarchical_rules() {
+        let mut lib = Library::new();
+        let arch = |a: &str| Arch::Code(CodeDialectArch {level: None, dialects: collection!{"spice".into() => CodeArch {
+            reference: format!("{}{{{{name}}}}", a),
+            definition: Definition::Primitive,
+        }}});
+        lib.insert(Entity {
+            name: "cell".into(),
+            symbol: Symbol {},
+            generic: Vec::new(),
+            port: Vec::new(),
+            archs: collection!{"a".into() => arch("a"), "b".into() => arch("b"), "c".into() => arch("c")},
+        }).unwrap();
+        let inst = |entity: &str| Instance {genericmap: IndexMap::new(), portmap: IndexMap::new(), x: 0, y: 0, entity: EntityRef::new(entity)};
+        let mut mid = Schematic {toplevel: false, level: None, instances: IndexMap::new()};
+        for name in ["x1", "x2", "y1"] {
+            mid.instances.insert(name.into(), inst("cell"));
+        }
+        lib.insert(Entity {
+            name: "mid".into(),
+            symbol: Symbol {},
+            generic: Vec::new(),
+            port: Vec::new(),
+            archs: collection!{"default".into() => Arch::Schematic(mid)},
+        }).unwrap();
+        let rule = |arch: &str| Configuration {arch: Some(arch.into()), ..Configuration::new(Ngspice, EntityRef::default())};
+        let mut conf = Configuration {
+            for_inst: collection!{
+                "m1".into() => Configuration {for_inst: collection!{"x1".into() => rule("a"), "x2".into() => rule("a")}, ..rule("default")},
+                "m1/x1".into() => rule("c"),
+                "**/y*".into() => rule("c"),
+                "m2/others".into() => rule("a"),
+            },
+            all: collection!{"cell".into() => "b".into()},
+            ..Configuration::new(Ngspice, EntityRef::new("mid"))
+        };
+        conf.resolve(&lib).unwrap();
+        let mid = Instance {entity: lib.get("mid").unwrap().clone().into(), ..inst("mid")};
+        let cells = match &lib.get("mid").unwrap().archs["default"] {
+            Arch::Schematic(sch) => &sch.instances,
+            Arch::Code(_) => unreachable!(),
+        };
+        let arch_of = |path: &str| {
+            let (top, sub) = path.split_once('/').unwrap();
+            let midconf = conf.get_conf(top, &mid);
+            let cellconf = midconf.get_conf(sub, &cells[sub]);
+            cellconf.reference("", &IndexMap::new(), &IndexMap::new()).unwrap()
+        };
+        // the enclosing path beats the nested instance rule
+        assert_eq!(arch_of("m1/x1"), "c");
+        // a nested instance rule beats the entity default
+        assert_eq!(arch_of("m1/x2"), "a");
+        // ** matches any depth
+        assert_eq!(arch_of("m1/y1"), "c");
+        // a glob beats others
+        assert_eq!(arch_of("m2/y1"), "c");
+        assert_eq!(arch_of("m2/x1"), "a");
+        assert_eq!(arch_of("m3/x1"), "b");
+        assert!(glob("n*s?", "nmos1"));
+        assert!(!glob("n*s?", "pmos1"));
+    }
+
+    #[test]
+    fn levels() {
+        let mut lib = Library::new();
+        let arch = |a: &str, level: Level| Arch::Code(CodeDialectArch {level: Some(level), dialects: collection!{"spice".into() => CodeArch {
+            reference: format!("{}{{{{name}}}}", a),
+            definition: Definition::Primitive,
+        }}});
+        let cell = lib.insert(Entity {
+            name: "cell".into(),
+            symbol: Symbol {},
+            generic: Vec::new(),
+            port: Vec::new(),
+            archs: collection!{
+                "tr".into() => arch("tr", Level::Transistor),
+                "beh".into() => arch("beh", Level::Behavioral),
+                "ideal".into() => arch("ideal", Level::Ideal),
+            },
+        }).unwrap();
+        let inst = Instance {genericmap: IndexMap::new(), portmap: IndexMap::new(), x: 0, y: 0, entity: cell.clone().into()};
+        let conf: Configuration<Ngspice> = serde_json::from_value(serde_json::json!({
+            "ent": "cell",
+            "levels": ["behavioral", "ideal"],
+            "for_inst": {"adc": {"levels": ["transistor"]}, "dac/**": {"levels": ["extracted"]}},
+        })).unwrap();
+        let arch_of = |path: &str| {
+            let (top, sub) = path.split_once('/').unwrap();
+            let topconf = conf.get_conf(top, &inst);
+            let subconf = topconf.get_conf(sub, &inst);
+            subconf.reference("", &IndexMap::new(), &IndexMap::new()).unwrap()
+        };
+        // behavioral everywhere except under adc
+        assert_eq!(arch_of("amp/x1"), "beh");
+        assert_eq!(arch_of("adc/x1"), "tr");
+        // no extracted view, so the first supported one
+        assert_eq!(arch_of("dac/x1"), "tr");
+        assert!(Level::Ideal < Level::Extracted);
+    }
 
     #[test]
     fn parse_ent() {
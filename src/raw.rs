@@ -0,0 +1,272 @@
+//! Readers for the SPICE3 raw files that batch runs of ngspice and Xyce write.
+//!
+//! A raw file has one or more plots, each a text header followed by
+//! its values as text or as little endian doubles.
+//! The plots are read into the same datasets as results from a simulator server.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::waveform::{Complex, Dataset, Signal};
+
+/// What went wrong reading a raw file
+#[derive(Debug)]
+pub enum Error {
+    Io { path: PathBuf, error: std::io::Error },
+    /// Not a raw file, or one with values that do not match its header
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io {path, error} => write!(f, "{}: {}", path.display(), error),
+            Error::Invalid(message) => write!(f, "invalid raw file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io {error, ..} => Some(error),
+            Error::Invalid(_) => None,
+        }
+    }
+}
+
+/// The results of one analysis
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plot {
+    pub title: String,
+    /// The kind of analysis, like `Transient Analysis`
+    pub name: String,
+    pub data: Dataset,
+}
+
+/// The header of the plot being read
+#[derive(Default)]
+struct Header {
+    title: String,
+    name: String,
+    complex: bool,
+    variables: usize,
+    points: usize,
+    names: Vec<String>,
+}
+
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Plot>, Error> {
+    let path = path.as_ref();
+    let input = std::fs::read(path).map_err(|error| Error::Io {path: path.into(), error})?;
+    parse(&input)
+}
+
+/// Read all plots. The values of a run that was interrupted end at the last complete point.
+pub fn parse(mut input: &[u8]) -> Result<Vec<Plot>, Error> {
+    let mut plots = Vec::new();
+    let mut header = Header::default();
+    while !input.is_empty() {
+        let line = next_line(&mut input)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once(':').ok_or_else(|| invalid(format!("expected a header, got {}", line)))?;
+        let value = value.trim();
+        match key.trim().to_lowercase().as_str() {
+            "title" => header.title = value.into(),
+            "plotname" => header.name = value.into(),
+            "flags" => header.complex = value.split_whitespace().any(|flag| flag.eq_ignore_ascii_case("complex")),
+            "no. variables" => header.variables = value.parse().map_err(|_| invalid(format!("number of variables {}", value)))?,
+            "no. points" => header.points = value.parse().map_err(|_| invalid(format!("number of points {}", value)))?,
+            "variables" => {
+                header.names.clear();
+                // some writers put the first variable on the same line
+                let mut line = value.to_string();
+                while header.names.len() < header.variables {
+                    if line.trim().is_empty() {
+                        line = next_line(&mut input)?;
+                        continue;
+                    }
+                    let name = line.split_whitespace().nth(1).ok_or_else(|| invalid(format!("variable {}", line.trim())))?;
+                    header.names.push(name.into());
+                    line.clear();
+                }
+            }
+            "values" | "binary" if header.names.is_empty() => return Err(invalid(format!("no variables in plot {}", header.name))),
+            kind @ ("values" | "binary") => {
+                let columns = if kind == "values" { ascii(&header, &mut input)? } else { binary(&header, &mut input) };
+                plots.push(header.plot(columns)?);
+                // the next plot has a header of its own
+                header = Header::default();
+            }
+            _ => {}
+        }
+    }
+    Ok(plots)
+}
+
+fn invalid(message: String) -> Error {
+    Error::Invalid(message)
+}
+
+/// The next text line, without its line ending
+fn next_line(input: &mut &[u8]) -> Result<String, Error> {
+    let end = input.iter().position(|&b| b == b'\n').unwrap_or(input.len());
+    let line = std::str::from_utf8(&input[..end]).map_err(|_| invalid("header is not text".into()))?;
+    *input = &input[(end + 1).min(input.len())..];
+    Ok(line.trim_end().into())
+}
+
+impl Header {
+    fn plot(&self, columns: Vec<Vec<Complex>>) -> Result<Plot, Error> {
+        let mut data = Dataset::new(self.names.first().map_or("", String::as_str));
+        for (name, column) in self.names.iter().zip(columns) {
+            let signal = if self.complex {
+                Signal::Complex(column)
+            } else {
+                Signal::Real(column.iter().map(|c| c.re).collect())
+            };
+            data.insert(name, signal).map_err(invalid)?;
+        }
+        Ok(Plot {title: self.title.clone(), name: self.name.clone(), data})
+    }
+}
+
+/// Text values, each point an index followed by a value per variable.
+/// Complex values are written as `re,im`.
+fn ascii(header: &Header, input: &mut &[u8]) -> Result<Vec<Vec<Complex>>, Error> {
+    let mut columns = vec![Vec::new(); header.variables];
+    let mut point = Vec::with_capacity(header.variables + 1);
+    while !input.is_empty() && (header.points == 0 || columns[0].len() < header.points) {
+        // the next plot starts with a header
+        if input[0].is_ascii_alphabetic() {
+            break;
+        }
+        for token in next_line(input)?.split_whitespace() {
+            point.push(token.to_string());
+            if point.len() == header.variables + 1 {
+                for (column, value) in columns.iter_mut().zip(&point[1..]) {
+                    column.push(parse_value(value, header.complex)?);
+                }
+                point.clear();
+            }
+        }
+    }
+    Ok(columns)
+}
+
+fn parse_value(value: &str, complex: bool) -> Result<Complex, Error> {
+    let number = |s: &str| s.parse::<f64>().map_err(|_| invalid(format!("value {}", value)));
+    if complex {
+        let (re, im) = value.split_once(',').ok_or_else(|| invalid(format!("complex value {}", value)))?;
+        Ok(Complex {re: number(re)?, im: number(im)?})
+    } else {
+        Ok(Complex {re: number(value)?, im: 0.0})
+    }
+}
+
+/// Binary values, a double per variable for each point, or two for complex ones.
+/// Without a number of points, the values go on to the end of the file.
+fn binary(header: &Header, input: &mut &[u8]) -> Vec<Vec<Complex>> {
+    let width = if header.complex { 16 } else { 8 };
+    let stride = header.variables * width;
+    let available = input.len().checked_div(stride).unwrap_or(0);
+    let points = if header.points == 0 { available } else { header.points.min(available) };
+    let double = |bytes: &[u8]| {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&bytes[..8]);
+        f64::from_le_bytes(buf)
+    };
+    let mut columns = vec![Vec::with_capacity(points); header.variables];
+    for point in input[..points * stride].chunks(stride) {
+        for (column, value) in columns.iter_mut().zip(point.chunks(width)) {
+            column.push(if header.complex {
+                Complex {re: double(value), im: double(&value[8..])}
+            } else {
+                Complex {re: double(value), im: 0.0}
+            });
+        }
+    }
+    // a run cut short leaves nothing but the rest of an interrupted point
+    *input = if points < header.points || header.points == 0 { &[] } else { &input[points * stride..] };
+    columns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASCII: &str = "Title: * inverter
+Date: Fri Oct 16 12:00:00  2026
+Plotname: Operating Point
+Flags: real
+No. Variables: 2
+No. Points: 1
+Variables:
+\t0\tv(out)\tvoltage
+\t1\tvdd#branch\tcurrent
+Values:
+ 0\t5.000000000000000e+00
+\t-1.000000000000000e-06
+
+Title: * inverter
+Date: Fri Oct 16 12:00:00  2026
+Plotname: AC Analysis
+Flags: complex
+No. Variables: 2
+No. Points: 2
+Variables:
+\t0\tfrequency\tfrequency grid=3
+\t1\tv(out)\tvoltage
+Values:
+ 0\t1.000000000000000e+03,0.000000000000000e+00
+\t-2.000000000000000e+00,1.000000000000000e-01
+ 1\t1.000000000000000e+04,0.000000000000000e+00
+\t-1.500000000000000e+00,5.000000000000000e-01
+";
+
+    #[test]
+    fn ascii_plots() {
+        let plots = parse(ASCII.as_bytes()).unwrap();
+        assert_eq!(plots.len(), 2);
+        assert_eq!(plots[0].name, "Operating Point");
+        assert_eq!(plots[0].data.scale(), "v(out)");
+        assert_eq!(plots[0].data.get("vdd#branch"), Some(&Signal::Real(vec![-1e-6])));
+        let ac = &plots[1].data;
+        assert_eq!(ac.scale_values().unwrap(), vec![1e3, 1e4]);
+        assert_eq!(ac.get("v(out)"), Some(&Signal::Complex(vec![Complex {re: -2.0, im: 0.1}, Complex {re: -1.5, im: 0.5}])));
+
+        // a plot without flags or a number of points is real, up to the next header
+        let next = "Plotname: DC transfer characteristic\nNo. Variables: 2\nVariables:\n\t0\tv(in)\tvoltage\n\t1\tv(out)\tvoltage\nValues:\n 0\t0.0\t5.0\n 1\t5.0\t0.0\n 2\t2.5\t2.5\n";
+        let plots = parse(format!("{}{}", ASCII, next).as_bytes()).unwrap();
+        assert_eq!(plots.len(), 3);
+        assert_eq!(plots[2].title, "");
+        assert_eq!(plots[2].data.get("v(out)"), Some(&Signal::Real(vec![5.0, 0.0, 2.5])));
+    }
+
+    #[test]
+    fn binary_plot() {
+        let mut input = b"Title: tb\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: 0\nVariables:\n\t0\tTIME\ttime\n\t1\tV(OUT)\tvoltage\nBinary:\n".to_vec();
+        for value in &[0.0f64, 0.0, 1e-6, 2.5, 2e-6, 5.0] {
+            input.extend_from_slice(&value.to_le_bytes());
+        }
+        // an interrupted run
+        input.extend_from_slice(&3e-6f64.to_le_bytes());
+        let plots = parse(&input).unwrap();
+        assert_eq!(plots.len(), 1);
+        let tran = &plots[0].data;
+        assert_eq!(tran.scale_values().unwrap(), vec![0.0, 1e-6, 2e-6]);
+        assert_eq!(tran.get("V(OUT)"), Some(&Signal::Real(vec![0.0, 2.5, 5.0])));
+
+        // the same run cut short of the points it announced
+        let mut input = b"Title: tb\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: 3\nVariables:\n\t0\tTIME\ttime\n\t1\tV(OUT)\tvoltage\nBinary:\n".to_vec();
+        for value in &[0.0f64, 0.0, 1e-6, 2.5, 2e-6] {
+            input.extend_from_slice(&value.to_le_bytes());
+        }
+        let plots = parse(&input).unwrap();
+        assert_eq!(plots.len(), 1);
+        assert_eq!(plots[0].data.scale_values().unwrap(), vec![0.0, 1e-6]);
+
+        assert!(matches!(parse(b"Flags: real\nNo. Points: many\n"), Err(Error::Invalid(_))));
+        assert!(parse(b"Plotname: Transient Analysis\nValues:\n").is_err());
+    }
+}
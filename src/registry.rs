@@ -0,0 +1,166 @@
+//! Simulators chosen at runtime.
+//!
+//! `Simulator` is generic so every backend can synthesize any configuration,
+//! which also makes it impossible to use as a trait object.
+//! `Backend` is its object safe counterpart for configurations of `Dynamic`,
+//! a simulator that forwards to whichever backend it was created with.
+
+use std::any::Any;
+use std::fmt;
+use crate::*;
+
+/// An object safe simulator, implemented for every `Simulator`
+pub trait Backend {
+    fn name(&self) -> &str;
+    fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch>;
+    fn node_path(&self, path: &str) -> String;
+    fn synthesize_definition(&self, conf: &Configuration<Dynamic>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError>;
+    fn synthesize_reference(&self, conf: &Configuration<Dynamic>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError>;
+    fn synthesize_declaration(&self, conf: &Configuration<Dynamic>) -> Result<String, CodeError>;
+}
+
+impl<T: Simulator> Backend for T {
+    fn name(&self) -> &str {
+        Simulator::name(self)
+    }
+    fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch> {
+        Simulator::get_dialect(self, arch)
+    }
+    fn node_path(&self, path: &str) -> String {
+        Simulator::node_path(self, path)
+    }
+    fn synthesize_definition(&self, conf: &Configuration<Dynamic>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+        Simulator::synthesize_definition(self, conf, ckt)
+    }
+    fn synthesize_reference(&self, conf: &Configuration<Dynamic>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        Simulator::synthesize_reference(self, conf, name, genericmap, portmap)
+    }
+    fn synthesize_declaration(&self, conf: &Configuration<Dynamic>) -> Result<String, CodeError> {
+        Simulator::synthesize_declaration(self, conf)
+    }
+}
+
+/// A simulator selected at runtime, usually from a `Registry`.
+/// The default is ngspice, as for a deserialized configuration that is not retargeted yet.
+#[derive(Copy, Clone)]
+pub struct Dynamic(pub &'static dyn Backend);
+
+impl Default for Dynamic {
+    fn default() -> Self {
+        Dynamic(&Ngspice)
+    }
+}
+
+impl fmt::Debug for Dynamic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Dynamic")
+    }
+}
+
+/// The configuration as it is passed to a `Dynamic` simulator, which is always its own
+fn dynamic<S: Simulator>(conf: &Configuration<S>) -> Result<&Configuration<Dynamic>, CodeError> {
+    (conf as &dyn Any).downcast_ref().ok_or_else(|| ErrorKind::Unsupported("configuration of another simulator".into()).into())
+}
+
+impl Simulator for Dynamic {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+    fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch> {
+        self.0.get_dialect(arch)
+    }
+    fn node_path(&self, path: &str) -> String {
+        self.0.node_path(path)
+    }
+    fn synthesize_definition<S: Simulator>(&self, conf: &Configuration<S>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+        self.0.synthesize_definition(dynamic(conf)?, ckt)
+    }
+    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        self.0.synthesize_reference(dynamic(conf)?, name, genericmap, portmap)
+    }
+    fn synthesize_declaration<S: Simulator>(&self, conf: &Configuration<S>) -> Result<String, CodeError> {
+        self.0.synthesize_declaration(dynamic(conf)?)
+    }
+}
+
+/// The simulators a user can choose from, by name
+pub struct Registry {
+    backends: IndexMap<String, &'static dyn Backend>,
+}
+
+impl Default for Registry {
+    /// All simulators of this crate
+    fn default() -> Self {
+        let mut reg = Registry::empty();
+        reg.register("ngspice", &Ngspice);
+        reg.register("xyce", &Xyce);
+        reg.register("ghdl", &Ghdl);
+        reg.register("verilator", &Verilator);
+        reg.register("cxxrtl", &Cxxrtl);
+        reg.register("amaranth", &Amaranth);
+        reg
+    }
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// A registry without any simulators
+    pub fn empty() -> Registry {
+        Registry {backends: IndexMap::new()}
+    }
+
+    /// Add a simulator, replacing any with the same name
+    pub fn register(&mut self, name: &str, backend: &'static dyn Backend) {
+        self.backends.insert(name.into(), backend);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Dynamic> {
+        self.backends.get(name).map(|&backend| Dynamic(backend))
+    }
+
+    /// The names of the simulators, in the order they were registered
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.backends.keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{entity, inverter_tb};
+
+    #[test]
+    fn runtime_selection() {
+        let mut lib = Library::new();
+        let mut arch = CodeDialectArch::new();
+        for dialect in ["spice", "vhdl"] {
+            arch.dialects.insert(dialect.into(), CodeArch {
+                definition: Definition::Primitive,
+                reference: format!("{} {{{{name}}}}", dialect),
+            });
+        }
+        let cell = lib.insert(entity("cell", Vec::new(), Vec::new(), collection!{"rtl".into() => Arch::Code(arch)})).unwrap();
+        let conf: Configuration<Dynamic> = Configuration::new(Dynamic::default(), cell.into());
+        let reg = Registry::new();
+        assert_eq!(reg.names().collect::<Vec<&str>>(), vec!["ngspice", "xyce", "ghdl", "verilator", "cxxrtl", "amaranth"]);
+        let reference = |sim: &str| conf.retarget(reg.get(sim).unwrap()).reference("u1", &IndexMap::new(), &IndexMap::new());
+        assert_eq!(reference("ngspice").unwrap(), "spice u1");
+        assert_eq!(reference("ghdl").unwrap(), "vhdl u1");
+        assert!(reference("verilator").is_err());
+        assert!(reg.get("spectre").is_none());
+
+        // the same tree, down to the schematics
+        let tb = inverter_tb();
+        let conf = Configuration::new(Dynamic::default(), tb.into());
+        let netlist = |sim: &str| match conf.retarget(reg.get(sim).unwrap()).definition().unwrap().pop() {
+            Some(Definition::Code(code)) => code,
+            _ => panic!("expected netlist"),
+        };
+        assert!(netlist("ngspice").contains("xinv1 vdd 0 in mid inverter w={wn}\n"));
+        assert!(netlist("xyce").contains("xinv1 vdd 0 in mid inverter params: w={wn}\n"));
+        assert_eq!(Simulator::node_path(&reg.get("xyce").unwrap(), "inv1/out"), "xinv1:out");
+    }
+}
@@ -0,0 +1,208 @@
+//! An async client for simulator servers, as described by `src/api/Simulator.capnp`.
+//!
+//! Enabled by the `simserver` feature. Cap'n Proto clients are not `Send`,
+//! so the RPC system returned by `Server::connect` has to be spawned on a local executor,
+//! such as a Tokio `LocalSet`:
+//!
+//! ```ignore
+//! let stream = tokio::net::TcpStream::connect(addr).await?;
+//! let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+//! let (sim, rpc) = Server::connect(reader, writer);
+//! tokio::task::spawn_local(rpc);
+//! let commands = sim.load(&conf.bundle("tb.sp")?).await?;
+//! let data = commands.tran(1e-6, 2e-3, 0.0, &[]).await?.dataset().await?;
+//! ```
+
+use capnp::capability::{FromClientHook, Response};
+use capnp::any_pointer;
+use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use futures::{AsyncRead, AsyncWrite, Stream, TryStreamExt};
+use crate::Bundle;
+use crate::waveform::{Complex, Dataset, Signal};
+
+#[allow(non_snake_case, clippy::all)]
+pub mod Simulator_capnp {
+    include!(concat!(env!("OUT_DIR"), "/src/api/Simulator_capnp.rs"));
+}
+
+use Simulator_capnp::{ac, op, result, run, simulator, tran, vector};
+pub use Simulator_capnp::AcType;
+
+/// A connection to a simulator server of any kind
+pub struct Server {
+    client: simulator::Client<any_pointer::Owned>,
+}
+
+impl Server {
+    /// Connect over a stream. The returned RPC system carries all calls, and has to be spawned.
+    pub fn connect<R, W>(reader: R, writer: W) -> (Server, RpcSystem<rpc_twoparty_capnp::Side>)
+    where
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        let network = twoparty::VatNetwork::new(reader, writer, rpc_twoparty_capnp::Side::Client, Default::default());
+        let mut rpc = RpcSystem::new(Box::new(network), None);
+        let client = rpc.bootstrap(rpc_twoparty_capnp::Side::Server);
+        (Server {client}, rpc)
+    }
+
+    /// Upload the files of a bundle, returning the commands of the simulator to run it
+    pub async fn load(&self, bundle: &Bundle) -> Result<Commands, capnp::Error> {
+        let mut request = self.client.load_files_request();
+        let mut files = request.get().init_files(bundle.files.len() as u32);
+        for (i, f) in bundle.files.iter().enumerate() {
+            let mut file = files.reborrow().get(i as u32);
+            file.set_name(&f.name);
+            file.set_contents(&f.contents);
+        }
+        let response = request.send().promise.await?;
+        Ok(Commands {response})
+    }
+}
+
+/// The commands of a loaded design.
+/// Ngspice has all of them, Xyce and CXXRTL only `run`,
+/// and calling a command the simulator does not have is an error.
+pub struct Commands {
+    response: Response<simulator::load_files_results::Owned<any_pointer::Owned>>,
+}
+
+impl Commands {
+    /// The commands as the interface that has the command
+    fn get<T: FromClientHook>(&self) -> Result<T, capnp::Error> {
+        self.response.get()?.get_commands()?.get_as_capability()
+    }
+
+    /// Run the analyses in the files. Empty `vectors` saves all of them.
+    pub async fn run(&self, vectors: &[&str]) -> Result<Results, capnp::Error> {
+        let mut request = self.get::<run::Client>()?.run_request();
+        set_vectors(request.get().init_vectors(vectors.len() as u32), vectors);
+        let response = request.send().promise.await?;
+        Ok(Results {client: response.get()?.get_result()?})
+    }
+
+    /// A transient analysis
+    pub async fn tran(&self, step: f64, stop: f64, start: f64, vectors: &[&str]) -> Result<Results, capnp::Error> {
+        let mut request = self.get::<tran::Client>()?.tran_request();
+        let mut params = request.get();
+        params.set_step(step);
+        params.set_stop(stop);
+        params.set_start(start);
+        set_vectors(params.init_vectors(vectors.len() as u32), vectors);
+        let response = request.send().promise.await?;
+        Ok(Results {client: response.get()?.get_result()?})
+    }
+
+    /// An operating point analysis
+    pub async fn op(&self, vectors: &[&str]) -> Result<Results, capnp::Error> {
+        let mut request = self.get::<op::Client>()?.op_request();
+        set_vectors(request.get().init_vectors(vectors.len() as u32), vectors);
+        let response = request.send().promise.await?;
+        Ok(Results {client: response.get()?.get_result()?})
+    }
+
+    /// A small signal analysis of `num` points from `fstart` to `fstop`
+    pub async fn ac(&self, mode: AcType, num: u64, fstart: f64, fstop: f64, vectors: &[&str]) -> Result<Results, capnp::Error> {
+        let mut request = self.get::<ac::Client>()?.ac_request();
+        let mut params = request.get();
+        params.set_mode(mode);
+        params.set_num(num);
+        params.set_fstart(fstart);
+        params.set_fstop(fstop);
+        set_vectors(params.init_vectors(vectors.len() as u32), vectors);
+        let response = request.send().promise.await?;
+        Ok(Results {client: response.get()?.get_result()?})
+    }
+}
+
+fn set_vectors(mut list: capnp::text_list::Builder, vectors: &[&str]) {
+    for (i, vector) in vectors.iter().enumerate() {
+        list.set(i as u32, vector);
+    }
+}
+
+/// The results of an analysis, read in chunks while the simulator is running
+pub struct Results {
+    client: result::Client,
+}
+
+impl Results {
+    /// The chunks until the simulator has no more
+    pub fn stream(self) -> impl Stream<Item=Result<Dataset, capnp::Error>> {
+        futures::stream::try_unfold(Some(self.client), |client| async move {
+            let client = match client {
+                Some(client) => client,
+                None => return Ok(None),
+            };
+            let response = client.read_request().send().promise.await?;
+            let read = response.get()?;
+            let chunk = read_chunk(read)?;
+            let next = if read.get_more() { Some(client) } else { None };
+            Ok::<_, capnp::Error>(Some((chunk, next)))
+        })
+    }
+
+    /// All chunks appended into one dataset
+    pub async fn dataset(self) -> Result<Dataset, capnp::Error> {
+        let mut data = Dataset::default();
+        let mut chunks = Box::pin(self.stream());
+        while let Some(chunk) = chunks.try_next().await? {
+            data.append(chunk).map_err(capnp::Error::failed)?;
+        }
+        Ok(data)
+    }
+}
+
+/// One chunk of results as a dataset
+fn read_chunk(read: result::read_results::Reader) -> Result<Dataset, capnp::Error> {
+    let mut chunk = Dataset::new(read.get_scale()?);
+    for vector in read.get_data()?.iter() {
+        chunk.insert(vector.get_name()?, read_signal(vector)?).map_err(capnp::Error::failed)?;
+    }
+    Ok(chunk)
+}
+
+fn read_signal(reader: vector::Reader) -> Result<Signal, capnp::Error> {
+    Ok(match reader.get_data().which()? {
+        vector::data::Real(data) => Signal::Real(data?.iter().collect()),
+        vector::data::Complex(data) => Signal::Complex(data?.iter().map(|c| Complex {re: c.get_real(), im: c.get_imag()}).collect()),
+        vector::data::Digital(data) => Signal::Digital(data?.iter().collect()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk() {
+        let mut message = capnp::message::Builder::new_default();
+        let mut read = message.init_root::<result::read_results::Builder>();
+        read.set_scale("frequency");
+        read.set_more(false);
+        let mut vectors = read.reborrow().init_data(3);
+        let mut frequency = vectors.reborrow().get(0);
+        frequency.set_name("frequency");
+        let mut values = frequency.init_data().init_real(2);
+        values.set(0, 1e3);
+        values.set(1, 1e4);
+        let mut out = vectors.reborrow().get(1);
+        out.set_name("v(out)");
+        let mut values = out.init_data().init_complex(2);
+        values.reborrow().get(0).set_real(-2.0);
+        values.reborrow().get(0).set_imag(0.1);
+        values.reborrow().get(1).set_real(-1.5);
+        values.reborrow().get(1).set_imag(0.5);
+        let mut clk = vectors.reborrow().get(2);
+        clk.set_name("clk");
+        let mut values = clk.init_data().init_digital(2);
+        values.set(0, false);
+        values.set(1, true);
+
+        let chunk = read_chunk(read.into_reader()).unwrap();
+        assert_eq!(chunk.scale(), "frequency");
+        assert_eq!(chunk.scale_values().unwrap(), vec![1e3, 1e4]);
+        assert_eq!(chunk.get("v(out)"), Some(&Signal::Complex(vec![Complex {re: -2.0, im: 0.1}, Complex {re: -1.5, im: 0.5}])));
+        assert_eq!(chunk.get("clk"), Some(&Signal::Digital(vec![false, true])));
+    }
+}
@@ -0,0 +1,324 @@
+//! The Handlebars templates that reference code architectures.
+//!
+//! Templates are compiled once into a registry shared by the thread,
+//! and rendered without HTML escaping. Besides the Handlebars built-ins there are
+//!
+//! * `add`, `sub`, `mul` and `div` on numbers or SPICE quantities, like `{{mul generic.w 2}}`
+//! * `si` to format a number with a scale suffix, so `{{si (mul generic.w 2)}}` gives `2u`
+//! * `num` to convert a quantity to a plain number
+//! * `default` for a value that may be empty, like `{{default generic.m 1}}`
+//! * `bits` for the nodes of a bus, like `{{#each (bits port.d)}}{{this}} {{/each}}`
+//! * `escape` for a string literal of a dialect, like `{{escape generic.file "vhdl"}}`
+//!
+//! Templates are checked against their entity when it is added to a library,
+//! as a misspelled port would otherwise render as nothing.
+
+use std::cell::RefCell;
+use handlebars::{Handlebars, Helper, HelperDef, JsonValue, Path, RenderContext, RenderError, ScopedJson, TemplateRenderError};
+use handlebars::template::{HelperTemplate, Parameter, Template, TemplateElement};
+use indexmap::IndexSet;
+use serde::Serialize;
+use crate::units::{format_si, parse_si};
+use crate::{Arch, CodeError, Entity, ErrorKind, Severity};
+
+thread_local! {
+    static REGISTRY: RefCell<Handlebars<'static>> = RefCell::new(registry());
+}
+
+/// A registry with the helpers of this module, that does not escape
+pub fn registry() -> Handlebars<'static> {
+    let mut reg = Handlebars::new();
+    reg.register_escape_fn(handlebars::no_escape);
+    reg.register_helper("add", Box::new(Function(|h| Ok(json_number(number(h, 0)? + number(h, 1)?)))));
+    reg.register_helper("sub", Box::new(Function(|h| Ok(json_number(number(h, 0)? - number(h, 1)?)))));
+    reg.register_helper("mul", Box::new(Function(|h| Ok(json_number(number(h, 0)? * number(h, 1)?)))));
+    reg.register_helper("div", Box::new(Function(|h| Ok(json_number(number(h, 0)? / number(h, 1)?)))));
+    reg.register_helper("si", Box::new(Function(|h| Ok(format_si(number(h, 0)?).into()))));
+    reg.register_helper("num", Box::new(Function(|h| Ok(json_number(number(h, 0)?)))));
+    reg.register_helper("default", Box::new(Function(default)));
+    reg.register_helper("bits", Box::new(Function(bits)));
+    reg.register_helper("escape", Box::new(Function(escape)));
+    reg
+}
+
+/// Render a template, compiling it the first time it is used
+pub fn render<T: Serialize>(template: &str, data: &T) -> Result<String, CodeError> {
+    REGISTRY.with(|reg| {
+        let mut reg = reg.borrow_mut();
+        if !reg.has_template(template) {
+            reg.register_template_string(template, template).map_err(TemplateRenderError::from)?;
+        }
+        Ok(reg.render(template, data).map_err(TemplateRenderError::from)?)
+    })
+}
+
+/// Check the reference templates of all code architectures of an entity
+pub fn check_entity(ent: &Entity) -> Vec<(Severity, ErrorKind)> {
+    ent.archs.values()
+        .flat_map(|arch| match arch {
+            Arch::Code(arch) => arch.dialects.iter().collect(),
+            Arch::Schematic(_) => Vec::new(),
+        })
+        .flat_map(|(dialect, arch)| check(ent, dialect, &arch.reference))
+        .collect()
+}
+
+/// Check a reference template against the ports and generics of its entity.
+/// Unknown references are errors, and ports that are not used are warnings.
+/// Empty templates are not checked, languages that instantiate entities directly do not use them.
+pub fn check(ent: &Entity, dialect: &str, template: &str) -> Vec<(Severity, ErrorKind)> {
+    if template.is_empty() {
+        return Vec::new();
+    }
+    let compiled = match Template::compile(template) {
+        Ok(compiled) => compiled,
+        Err(e) => return vec![(Severity::Error, ErrorKind::Template(Box::new(e.into())))],
+    };
+    let mut paths = Vec::new();
+    references(&compiled, true, &mut paths);
+    let mut found = Vec::new();
+    let mut used = IndexSet::new();
+    for path in paths {
+        let known = match path.as_slice() {
+            [root] => ["name", "port", "generic"].contains(&root.as_str()),
+            [root, port, ..] if root == "port" => {
+                used.insert(port.clone());
+                ent.port.iter().any(|p| &p.name == port)
+            }
+            [root, generic, ..] if root == "generic" => ent.generic.iter().any(|g| &g.name == generic),
+            _ => false,
+        };
+        if path.as_slice() == ["port"] {
+            used.extend(ent.port.iter().map(|p| p.name.clone()));
+        }
+        if !known {
+            found.push((Severity::Error, ErrorKind::UnknownReference {entity: ent.name.clone(), dialect: dialect.into(), reference: path.join(".")}));
+        }
+    }
+    for p in ent.port.iter().filter(|p| !used.contains(&p.name)) {
+        found.push((Severity::Warning, ErrorKind::UnusedTemplatePort {entity: ent.name.clone(), dialect: dialect.into(), port: p.name.clone()}));
+    }
+    found
+}
+
+/// Collect the paths a template looks up in the reference arguments, as segments.
+/// Inside `each` and `with` blocks only the paths to the outer context are collected.
+fn references(template: &Template, top: bool, paths: &mut Vec<Vec<String>>) {
+    for element in &template.elements {
+        match element {
+            TemplateElement::HTMLExpression(param) => parameter(param, top, paths),
+            TemplateElement::Expression(helper) | TemplateElement::HelperBlock(helper) => helper_references(helper, top, paths),
+            _ => (),
+        }
+    }
+}
+
+fn helper_references(helper: &HelperTemplate, top: bool, paths: &mut Vec<Vec<String>>) {
+    // a plain `{{port.a}}` is a helper named by its path
+    if let Parameter::Path(_) = helper.name {
+        parameter(&helper.name, top, paths);
+    }
+    for param in helper.params.iter().chain(helper.hash.values()) {
+        parameter(param, top, paths);
+    }
+    let scoped = matches!(&helper.name, Parameter::Name(name) if name == "each" || name == "with");
+    if let Some(block) = &helper.template {
+        references(block, top && !scoped, paths);
+    }
+    if let Some(inverse) = &helper.inverse {
+        references(inverse, top, paths);
+    }
+}
+
+fn parameter(param: &Parameter, top: bool, paths: &mut Vec<Vec<String>>) {
+    match param {
+        Parameter::Path(Path::Relative((_, raw))) => {
+            let mut rest = raw.as_str();
+            let mut outer = top;
+            loop {
+                if let Some(r) = rest.strip_prefix("../").or_else(|| rest.strip_prefix("@root.")).or_else(|| rest.strip_prefix("@root/")) {
+                    rest = r;
+                    outer = true;
+                } else if let Some(r) = rest.strip_prefix("./").or_else(|| rest.strip_prefix("this.")).or_else(|| rest.strip_prefix("this/")) {
+                    rest = r;
+                } else {
+                    break;
+                }
+            }
+            if outer && !rest.is_empty() && rest != "this" {
+                paths.push(segments(rest));
+            }
+        }
+        Parameter::Subexpression(sub) => if let TemplateElement::Expression(helper) = sub.element.as_ref() {
+            helper_references(helper, top, paths);
+        },
+        _ => (),
+    }
+}
+
+/// The segments of a path like `port.a` or `port/[a b]`
+fn segments(path: &str) -> Vec<String> {
+    let mut segs = Vec::new();
+    let mut rest = path;
+    while !rest.is_empty() {
+        let (seg, tail) = match rest.strip_prefix('[') {
+            Some(quoted) => {
+                let end = quoted.find(']').unwrap_or(quoted.len());
+                (&quoted[..end], quoted.get(end + 1..).unwrap_or_default())
+            }
+            None => rest.split_at(rest.find(['.', '/']).unwrap_or(rest.len())),
+        };
+        segs.push(seg.to_string());
+        rest = tail.strip_prefix(['.', '/']).unwrap_or(tail);
+    }
+    segs
+}
+
+/// A helper that computes a value from its parameters
+struct Function(fn(&Helper) -> Result<JsonValue, RenderError>);
+
+impl HelperDef for Function {
+    fn call_inner<'reg: 'rc, 'rc>(&self, h: &Helper<'reg, 'rc>, _: &'reg Handlebars<'reg>, _: &'rc handlebars::Context, _: &mut RenderContext<'reg, 'rc>) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        Ok(Some(ScopedJson::Derived((self.0)(h)?)))
+    }
+}
+
+fn param<'a>(h: &'a Helper, index: usize) -> Result<&'a JsonValue, RenderError> {
+    h.param(index)
+        .map(|p| p.value())
+        .ok_or_else(|| RenderError::new(format!("`{}` helper: missing parameter {}", h.name(), index + 1)))
+}
+
+/// A parameter that is a number, or a string with a SPICE quantity
+fn number(h: &Helper, index: usize) -> Result<f64, RenderError> {
+    let value = param(h, index)?;
+    match value {
+        JsonValue::Number(num) => num.as_f64(),
+        JsonValue::String(val) => parse_si(val, None),
+        _ => None,
+    }.ok_or_else(|| RenderError::new(format!("`{}` helper: {} is not a number", h.name(), value)))
+}
+
+/// Whole numbers render without fraction, so `{{mul 2 3}}` gives `6`
+fn json_number(value: f64) -> JsonValue {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        JsonValue::from(value as i64)
+    } else {
+        JsonValue::from(value)
+    }
+}
+
+/// The first parameter, or the second if the first is missing or empty
+fn default(h: &Helper) -> Result<JsonValue, RenderError> {
+    let value = param(h, 0)?;
+    if value.is_null() || value.as_str() == Some("") {
+        Ok(param(h, 1)?.clone())
+    } else {
+        Ok(value.clone())
+    }
+}
+
+/// The nodes of a bus port, which is passed to templates separated by spaces
+fn bits(h: &Helper) -> Result<JsonValue, RenderError> {
+    match param(h, 0)? {
+        JsonValue::String(nodes) => Ok(nodes.split_whitespace().collect::<Vec<&str>>().into()),
+        JsonValue::Array(nodes) => Ok(JsonValue::Array(nodes.clone())),
+        value => Err(RenderError::new(format!("`bits` helper: {} is not a bus", value))),
+    }
+}
+
+/// A value as a string literal of the dialect named by the second parameter
+fn escape(h: &Helper) -> Result<JsonValue, RenderError> {
+    let text = match param(h, 0)? {
+        JsonValue::String(text) => text.clone(),
+        value => value.to_string(),
+    };
+    let dialect = param(h, 1)?.as_str().unwrap_or_default();
+    let literal = match dialect {
+        "vhdl" | "ghdl" => format!("\"{}\"", text.replace('"', "\"\"")),
+        "verilog" | "verilator" | "cxxrtl" | "amaranth" => format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"")),
+        // SPICE strings have no way to escape a quote
+        "spice" | "ngspice" | "xyce" if !text.contains('"') => format!("\"{}\"", text),
+        "spice" | "ngspice" | "xyce" => return Err(RenderError::new(format!("`escape` helper: quote in SPICE string {}", text))),
+        _ => return Err(RenderError::new(format!("`escape` helper: unknown dialect {}", dialect))),
+    };
+    Ok(literal.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+    use crate::*;
+    use crate::tests::{code, entity, instance, schematic};
+
+    #[derive(Serialize)]
+    struct Args {
+        name: &'static str,
+        generic: IndexMap<&'static str, &'static str>,
+        port: IndexMap<&'static str, &'static str>,
+    }
+
+    #[test]
+    fn helpers() {
+        let args = Args {
+            name: "1",
+            generic: collection!{"w" => "1u", "m" => "", "file" => "a \"b\".txt", "expr" => "{wn}"},
+            port: collection!{"d" => "q_0 q_1 q_2", "g" => "a&b"},
+        };
+        let render = |template: &str| render(template, &args);
+        assert_eq!(render("m{{name}} {{port.g}} W={{si (mul generic.w 2)}} L={{mul 2 3}}").unwrap(), "m1 a&b W=2u L=6");
+        assert_eq!(render("{{num generic.w}} {{si (div 1 4)}} {{add 1 (sub \"4.7k\" 700)}}").unwrap(), "1e-6 250m 4001");
+        assert_eq!(render("M={{default generic.m 1}} L={{default generic.l \"1u\"}} W={{default generic.w 2}}").unwrap(), "M=1 L=1u W=1u");
+        assert_eq!(render("{{#each (bits port.d)}}{{@index}}:{{this}}{{#unless @last}}, {{/unless}}{{/each}}").unwrap(), "0:q_0, 1:q_1, 2:q_2");
+        assert_eq!(render("{{escape generic.file \"vhdl\"}} {{escape generic.file \"verilog\"}}").unwrap(), "\"a \"\"b\"\".txt\" \"a \\\"b\\\".txt\"");
+        assert!(render("{{escape generic.file \"spice\"}}").is_err());
+        let err = render("{{mul generic.expr 2}}").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Template(_)));
+        assert!(render("{{mul generic.w}").is_err());
+    }
+
+    #[test]
+    fn validation() {
+        let nmos = |reference: &str| entity("nmos", vec![Generic::new("w", "1u"), Generic::new("l", "1u")], vec!["d".into(), "g".into(), "s".into(), "b".into()], collection!{"default".into() => code("spice", Definition::Primitive, reference)});
+        let found = |template: &str| -> Vec<String> {
+            check(&nmos(template), "spice", template).iter()
+                .map(|(severity, kind)| format!("{:?}: {}", severity, kind))
+                .collect()
+        };
+        assert!(found("m{{name}} {{port.d}} {{port.g}} {{port.s}} {{port.b}} NMOS W={{si (mul generic.w 2)}} L={{generic.l}}").is_empty());
+        assert_eq!(found("m{{name}} {{port.dd}} {{port.g}} {{port/[s]}} {{port.b}} NMOS W={{generic.W}} {{nane}}"), vec![
+            "Error: spice reference of nmos uses unknown port.dd",
+            "Error: spice reference of nmos uses unknown generic.W",
+            "Error: spice reference of nmos uses unknown nane",
+            "Warning: spice reference of nmos does not use port d",
+        ]);
+        // only paths out of the block refer to the arguments
+        assert!(found("m{{name}} {{#each port}}{{this}} {{/each}}{{#with generic}}W={{w}} {{../generic.l}}{{/with}}").is_empty());
+        assert_eq!(found("{{#each port}}{{../port.x}}{{/each}}"), vec!["Error: spice reference of nmos uses unknown port.x"]);
+        assert!(found("{{port.d").first().unwrap().starts_with("Error: template error"));
+        assert!(found("").is_empty());
+
+        let mut lib = Library::new();
+        match lib.insert(nmos("m{{name}} {{port.dd}}")) {
+            Err(err) => assert!(matches!(err.kind, ErrorKind::UnknownReference {..})),
+            Ok(_) => panic!("expected an unknown reference"),
+        }
+        assert!(lib.diagnostics().is_empty());
+        // a template that skips ports loads, with a warning for each of them
+        assert!(lib.insert(nmos("m{{name}} {{port.d}} {{port.g}} {{port.s}} {{generic.w}}")).is_ok());
+        let found: Vec<String> = lib.diagnostics().iter().map(|d| d.to_string()).collect();
+        assert_eq!(found, vec!["warning: spice reference of nmos does not use port b"]);
+
+        // and once more for a design that uses it, however many instances there are
+        let inst = |d: &str| instance("nmos", IndexMap::new(), collection!{"d".into() => d.into(), "g".into() => "g".into(), "s".into() => "0".into(), "b".into() => "0".into()});
+        let tb = lib.insert(entity("tb", Vec::new(), Vec::new(), collection!{"default".into() => schematic(true, collection!{
+            "m1".into() => inst("a"),
+            "m2".into() => inst("b"),
+        })})).unwrap();
+        let mut diagnostics = Vec::new();
+        Configuration::new(Ngspice, tb.into()).diagnose(&mut diagnostics);
+        let found: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(found, vec!["warning: tb/m1 nmos(default) for ngspice: spice reference of nmos does not use port b"]);
+    }
+}
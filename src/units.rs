@@ -0,0 +1,79 @@
+//! SPICE style numbers with SI scale suffixes, such as `1u`, `4.7k` or `10meg`.
+
+/// The scale suffixes understood by SPICE, longest first so `meg` wins over `m`.
+const SCALES: [(&str, f64); 10] = [
+    ("meg", 1e6),
+    ("mil", 25.4e-6),
+    ("t", 1e12),
+    ("g", 1e9),
+    ("k", 1e3),
+    ("m", 1e-3),
+    ("u", 1e-6),
+    ("n", 1e-9),
+    ("p", 1e-12),
+    ("f", 1e-15),
+];
+
+/// Parse a number with an optional scale suffix and unit, like `1.5uF`.
+/// Like SPICE, matching is case insensitive and the scale suffix takes precedence,
+/// so `1m` is a milli-something even if the unit is meters.
+/// Trailing letters after the scale must be the unit, if one is given.
+pub fn parse_si(value: &str, unit: Option<&str>) -> Option<f64> {
+    let value = value.trim().to_lowercase();
+    let split = value.find(|c: char| !(c.is_ascii_digit() || "+-.e".contains(c))).unwrap_or(value.len());
+    let (number, rest) = value.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let (scale, rest) = SCALES.iter()
+        .find(|(suffix, _)| rest.starts_with(suffix))
+        .map(|(suffix, scale)| (*scale, &rest[suffix.len()..]))
+        .unwrap_or((1.0, rest));
+    if rest.is_empty() || Some(rest) == unit.map(str::to_lowercase).as_deref() {
+        Some(number * scale)
+    } else {
+        None
+    }
+}
+
+/// Format a number with the scale suffix that leaves between 1 and 1000 in front of it,
+/// like `4.7k`. The result is parsed back by `parse_si`.
+pub fn format_si(value: f64) -> String {
+    // the largest scale below the value, or femto for smaller values
+    let (suffix, scale) = SCALES.iter()
+        .chain(&[("", 1.0)])
+        .filter(|(suffix, scale)| *suffix != "mil" && value.abs() >= *scale)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .copied()
+        .unwrap_or(if value == 0.0 { ("", 1.0) } else { ("f", 1e-15) });
+    let number = format!("{:.6}", value / scale);
+    let number = number.trim_end_matches('0').trim_end_matches('.');
+    format!("{}{}", number, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn si() {
+        assert_eq!(parse_si("1", None), Some(1.0));
+        assert_eq!(parse_si("2.5k", None), Some(2500.0));
+        assert_eq!(parse_si("10MEG", None), Some(10e6));
+        assert_eq!(parse_si("1e-3", None), Some(1e-3));
+        assert_eq!(parse_si("1.5uF", Some("F")), Some(1.5e-6));
+        assert_eq!(parse_si("3V", Some("V")), Some(3.0));
+        assert_eq!(parse_si("1uF", None), None);
+        assert_eq!(parse_si("1uV", Some("F")), None);
+        assert_eq!(parse_si("abc", None), None);
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(format_si(2e-6), "2u");
+        assert_eq!(format_si(4700.0), "4.7k");
+        assert_eq!(format_si(10e6), "10meg");
+        assert_eq!(format_si(-1.5e-3), "-1.5m");
+        assert_eq!(format_si(42.0), "42");
+        assert_eq!(format_si(0.0), "0");
+        assert_eq!(format_si(33e-9), "33n");
+    }
+}
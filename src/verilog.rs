@@ -0,0 +1,239 @@
+//! A Verilog backend, to simulate digital hierarchies with Verilator.
+//!
+//! Verilog has no configurations, so schematics become structural modules
+//! that instantiate the entity chosen by the `Configuration` directly,
+//! with named port connections and generics passed as parameters.
+
+use crate::*;
+
+#[derive(Copy, Clone, Default)]
+pub struct Verilator;
+
+impl Simulator for Verilator {
+    fn name(&self) -> &str {
+        "verilator"
+    }
+    fn get_dialect<'a>(&self, arch: &'a CodeDialectArch) -> Option<&'a CodeArch> {
+        arch.dialects.get("verilator").or_else(|| arch.dialects.get("verilog"))
+    }
+    fn synthesize_definition<S: Simulator>(&self, conf: &Configuration<S>, ckt: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+        definition(conf, ckt)
+    }
+    fn synthesize_reference<S: Simulator>(&self, conf: &Configuration<S>, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+        instantiation(conf.ent.get()?, name, genericmap, portmap)
+    }
+}
+
+impl Verilator {
+    /// The sources of a toplevel entity and a CMake project to verilate them, which is the entry.
+    /// The project builds a C++ testbench in `main.cpp` against the generated `V<entity>.h`,
+    /// so together with a `main.cpp` these files are ready to load into a simulator server.
+    pub fn bundle<S: Simulator>(conf: &Configuration<S>) -> Result<Bundle, CodeError> {
+        let name = conf.ent.name();
+        let mut bundle = Bundle::new("CMakeLists.txt");
+        let (code, libs) = sources(conf)?;
+        bundle.add(&format!("{}.v", name), code);
+        let mut sources = vec![format!("${{CMAKE_SOURCE_DIR}}/{}.v", name)];
+        for lib in libs {
+            sources.push(format!("${{CMAKE_SOURCE_DIR}}/{}", bundle.add_library(&lib)?));
+        }
+        let cmake = format!("cmake_minimum_required(VERSION 3.12)\n\n\
+            project (testbench)\n\n\
+            find_package(verilator HINTS $ENV{{VERILATOR_ROOT}})\n\n\
+            add_executable(testbench main.cpp)\n\
+            verilate(testbench SOURCES {} TOP_MODULE {})\n", sources.join(" "), name);
+        bundle.add("CMakeLists.txt", cmake);
+        Ok(bundle)
+    }
+}
+
+/// The generated Verilog of a configuration in one source,
+/// and the library files it needs
+pub(crate) fn sources<S: Simulator>(conf: &Configuration<S>) -> Result<(String, Vec<PathBuf>), CodeError> {
+    let mut code = String::new();
+    let mut libs = Vec::new();
+    for def in conf.definition()? {
+        match def {
+            Definition::Code(def) => {
+                code.push_str(&def);
+                if !def.ends_with('\n') {
+                    code.push('\n');
+                }
+            }
+            Definition::Library(lib) => libs.push(lib),
+            Definition::Primitive => (),
+        }
+    }
+    Ok((code, libs))
+}
+
+/// The reserved words of Verilog, which can not name modules, instances, ports or nets
+const KEYWORDS: [&str; 124] = [
+    "always", "and", "assign", "automatic", "begin", "buf", "bufif0", "bufif1", "case", "casex", "casez", "cell",
+    "cmos", "config", "deassign", "default", "defparam", "design", "disable", "edge", "else", "end", "endcase",
+    "endconfig", "endfunction", "endgenerate", "endmodule", "endprimitive", "endspecify", "endtable", "endtask",
+    "event", "for", "force", "forever", "fork", "function", "generate", "genvar", "highz0", "highz1", "if",
+    "ifnone", "incdir", "include", "initial", "inout", "input", "instance", "integer", "join", "large", "liblist",
+    "library", "localparam", "macromodule", "medium", "module", "nand", "negedge", "nmos", "nor",
+    "noshowcancelled", "not", "notif0", "notif1", "or", "output", "parameter", "pmos", "posedge", "primitive",
+    "pull0", "pull1", "pulldown", "pullup", "pulsestyle_ondetect", "pulsestyle_onevent", "rcmos", "real",
+    "realtime", "reg", "release", "repeat", "rnmos", "rpmos", "rtran", "rtranif0", "rtranif1", "scalared",
+    "showcancelled", "signed", "small", "specify", "specparam", "strong0", "strong1", "supply0", "supply1",
+    "table", "task", "time", "tran", "tranif0", "tranif1", "tri", "tri0", "tri1", "triand", "trior", "trireg",
+    "unsigned", "use", "uwire", "vectored", "wait", "wand", "weak0", "weak1", "while", "wire", "wor", "xnor", "xor",
+];
+
+/// A name as a Verilog identifier, which must not be a reserved word
+fn identifier(name: &str) -> Result<&str, CodeError> {
+    if KEYWORDS.contains(&name) {
+        return Err(ErrorKind::Unsupported(format!("{} is a reserved word in Verilog", name)).into());
+    }
+    Ok(name)
+}
+
+pub(crate) fn definition<S: Simulator>(conf: &Configuration<S>, sch: &Schematic) -> Result<IndexSet<Definition>, CodeError> {
+    let mut defs = conf.instance_definitions(sch)?;
+    let ent = conf.ent.get()?;
+    let mut res = format!("module {}", identifier(&ent.name)?);
+    res.push_str(&interface(ent)?);
+    for (net, Net {port, range}) in conf.internal_nets(sch)? {
+        let range = range.map(|(left, right)| format!("[{}:{}]", left, right));
+        res.push_str(&format!("  {} {};\n", net_type(port, range)?, identifier(&net)?));
+    }
+    for (name, inst) in &sch.instances {
+        // there is no configuration, so the bound entity is instantiated directly
+        let subconf = conf.get_conf(name, inst);
+        res.push_str(&subconf.located(|| {
+            let genericmap = subconf.instance_generics(inst)?;
+            instantiation(subconf.ent.get()?, name, &genericmap, &subconf.instance_ports(inst)?)
+        })?);
+    }
+    res.push_str("endmodule\n");
+    defs.insert(Definition::Code(res));
+    Ok(defs)
+}
+
+/// The parameter and port lists of a module header, up to and including the semicolon
+fn interface(ent: &Entity) -> Result<String, CodeError> {
+    let mut res = String::new();
+    if !ent.generic.is_empty() {
+        let params = ent.generic.iter().map(|g| {
+            // Verilog parameters always have a default
+            let default = match (&g.default, g.kind) {
+                (Some(default), _) => value(ent, g, default)?,
+                (None, GenericKind::String) => "\"\"".into(),
+                (None, _) => "0".into(),
+            };
+            Ok(match g.kind {
+                GenericKind::Integer => format!("  parameter integer {} = {}", g.name, default),
+                GenericKind::Real | GenericKind::Quantity => format!("  parameter real {} = {}", g.name, default),
+                GenericKind::String => format!("  parameter {} = {}", g.name, default),
+            })
+        }).collect::<Result<Vec<String>, CodeError>>()?;
+        res.push_str(&format!(" #(\n{}\n)", params.join(",\n")));
+    }
+    if !ent.port.is_empty() {
+        let ports = ent.port.iter().map(|p| {
+            let range = p.range.as_ref().map(|r| format!("[{}:{}]", r.left, r.right));
+            let mode = match p.direction {
+                Direction::In => "input",
+                Direction::Out => "output",
+                Direction::InOut => "inout",
+            };
+            Ok(format!("  {} {} {}", mode, net_type(p, range)?, identifier(&p.name)?))
+        }).collect::<Result<Vec<String>, CodeError>>()?;
+        res.push_str(&format!(" (\n{}\n)", ports.join(",\n")));
+    }
+    res.push_str(";\n");
+    Ok(res)
+}
+
+/// The Verilog net type of a port, with the range of a bus
+fn net_type(port: &Port, range: Option<String>) -> Result<String, CodeError> {
+    let typ = match &port.nature {
+        Nature::Logic => "wire",
+        Nature::Custom(typ) => typ,
+        Nature::Electrical => return Err(ErrorKind::Unsupported(format!("electrical port {} can not be simulated in Verilog", port.name)).into()),
+    };
+    Ok(match range {
+        Some(range) => format!("{} {}", typ, range),
+        None => typ.into(),
+    })
+}
+
+/// A net as a Verilog expression, with a list of nets concatenated
+fn net(net: &str) -> String {
+    if is_concat(net) {
+        format!("{{{}}}", net.split_whitespace().collect::<Vec<&str>>().join(", "))
+    } else {
+        net.into()
+    }
+}
+
+/// A parameter value in Verilog syntax
+fn value(ent: &Entity, g: &Generic, val: &str) -> Result<String, CodeError> {
+    Ok(match literal(ent, g, val)? {
+        Literal::Expr(val) | Literal::Other(val) => val.into(),
+        Literal::Real(num) => format!("{:?}", num),
+        Literal::String(val) => format!("\"{}\"", val),
+    })
+}
+
+/// A module instantiation with named parameters and port connections
+pub(crate) fn instantiation(ent: &Entity, name: &str, genericmap: &IndexMap<String, String>, portmap: &IndexMap<String, String>) -> Result<String, CodeError> {
+    if let Some(g) = genericmap.keys().find(|g| !ent.generic.iter().any(|eg| &eg.name == *g)) {
+        return Err(ErrorKind::UnknownGeneric {entity: ent.name.clone(), generic: g.clone()}.into());
+    }
+    let mut res = format!("  {}", identifier(&ent.name)?);
+    let params = ent.generic.iter()
+        .filter_map(|g| genericmap.get(&g.name).map(|val| Ok(format!(".{}({})", g.name, value(ent, g, val)?))))
+        .collect::<Result<Vec<String>, CodeError>>()?;
+    if !params.is_empty() {
+        res.push_str(&format!(" #({})", params.join(", ")));
+    }
+    let ports = ent.port.iter().map(|p| {
+        portmap.get(&p.name)
+            .map(|n| format!(".{}({})", p.name, net(n)))
+            .ok_or_else(|| ErrorKind::MissingPort {entity: ent.name.clone(), port: p.name.clone()}.into())
+    }).collect::<Result<Vec<String>, CodeError>>()?;
+    res.push_str(&format!(" {} ({});\n", identifier(name)?, ports.join(", ")));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{code, repeater_tb};
+
+    #[test]
+    fn buffer() {
+        let inv = code("verilog", Definition::Code("module inv #(parameter real delay = 1e-9) (input a, output y); assign y = ~a; endmodule\n".into()), "  inv {{name}} (.a({{port.a}}), .y({{port.y}}));\n");
+        let a = Port {range: Some(BusRange::new("1", RangeDirection::Downto, "0")), ..Port::new("a", Direction::In, Nature::Logic)};
+        let (lib, tb) = repeater_tb(inv, Vec::new(), a, "a[0]", "2n", "x0 x1");
+        let conf = Configuration::new(Verilator, tb.into());
+        let repeater = "module repeater (\n  input wire [1:0] a,\n  output wire y\n);\n\
+            \x20 wire mid;\n\
+            \x20 inv #(.delay(2e-9)) inv1 (.a(a[0]), .y(mid));\n\
+            \x20 inv inv2 (.a(mid), .y(y));\n\
+            endmodule\n";
+        let tb = "module tb;\n\
+            \x20 wire x0;\n  wire x1;\n  wire z;\n\
+            \x20 repeater b1 (.a({x0, x1}), .y(z));\n\
+            endmodule\n";
+        assert_eq!(conf.definition().unwrap(), indexset!{
+            Definition::Code("module inv #(parameter real delay = 1e-9) (input a, output y); assign y = ~a; endmodule\n".into()),
+            Definition::Code(repeater.into()),
+            Definition::Code(tb.into()),
+        });
+        let bundle = Verilator::bundle(&conf).unwrap();
+        assert_eq!(bundle.entry, "CMakeLists.txt");
+        assert_eq!(bundle.files[0].name, "tb.v");
+        assert!(bundle.files[0].contents.ends_with(tb.as_bytes()));
+        assert!(String::from_utf8_lossy(&bundle.get("CMakeLists.txt").unwrap().contents).contains("verilate(testbench SOURCES ${CMAKE_SOURCE_DIR}/tb.v TOP_MODULE tb)\n"));
+        let inv = lib.get("inv").unwrap();
+        // gate primitives are reserved
+        assert!(matches!(instantiation(inv, "buf", &IndexMap::new(), &collection!{"a".into() => "x".into(), "y".into() => "z".into()}).map_err(|e| e.kind),
+            Err(ErrorKind::Unsupported(_))));
+        assert!(identifier("buffer").is_ok());
+    }
+}
@@ -0,0 +1,473 @@
+//! Import of VHDL design units, parsed with vhdl_lang.
+
+use std::path::Path;
+use vhdl_lang::{ast, Diagnostic, Severity, Source, VHDLParser};
+use indexmap::IndexMap;
+use crate::*;
+
+/// Parse a VHDL file and convert all its entity declarations
+pub fn parse_entities(path: &Path) -> Result<Vec<Entity>, CodeError> {
+    let source = Source::from_latin1_file(path).map_err(|error| ErrorKind::Io {path: path.into(), error})?;
+    Ok(entities(&parse(&source)?))
+}
+
+/// Parse VHDL code and convert all its entity declarations
+pub fn parse_entities_str(code: &str) -> Result<Vec<Entity>, CodeError> {
+    let source = Source::inline(Path::new("inline.vhdl"), code);
+    Ok(entities(&parse(&source)?))
+}
+
+/// Parse a VHDL file and convert all its configuration declarations,
+/// binding instances to the entities in the library
+pub fn parse_configurations<S: Simulator + Default>(path: &Path, lib: &Library) -> Result<IndexMap<String, Configuration<S>>, CodeError> {
+    let bytes = std::fs::read(path).map_err(|error| ErrorKind::Io {path: path.into(), error})?;
+    // VHDL files are latin-1
+    let code: String = bytes.iter().map(|&b| b as char).collect();
+    let source = Source::inline(path, &binding_semicolons(&code));
+    configurations(&parse(&source)?, lib)
+}
+
+/// Parse VHDL code and convert all its configuration declarations
+pub fn parse_configurations_str<S: Simulator + Default>(code: &str, lib: &Library) -> Result<IndexMap<String, Configuration<S>>, CodeError> {
+    let source = Source::inline(Path::new("inline.vhdl"), &binding_semicolons(code));
+    configurations(&parse(&source)?, lib)
+}
+
+/// Drop the semicolon that is easily put between the entity aspect of a binding
+/// and its port or generic map, as in `use entity work.HA2(GATE); port map (...)`.
+/// Only configuration declarations are repaired: no item of theirs starts with a map,
+/// while a block header rightly has one after its generic or port clause.
+fn binding_semicolons(code: &str) -> String {
+    let tokens = tokens(code);
+    let text = |i: usize| tokens.get(i).map_or("", |&(start, end)| &code[start..end]);
+    let is = |i: usize, word: &str| text(i).eq_ignore_ascii_case(word);
+    let mut stray = Vec::new();
+    let mut configuration = false;
+    let mut i = 0;
+    while i < tokens.len() {
+        if !configuration {
+            configuration = is(i, "configuration") && is(i + 2, "of");
+        } else if is(i, "end") {
+            // only the end of the declaration itself is not followed by `for`
+            configuration = is(i + 1, "for");
+            i += 1;
+        } else if text(i) == ";" && (is(i + 1, "port") || is(i + 1, "generic")) && is(i + 2, "map") {
+            stray.push(tokens[i].0);
+        }
+        i += 1;
+    }
+    code.char_indices()
+        .filter(|(i, _)| !stray.contains(i))
+        .map(|(_, c)| c)
+        .collect()
+}
+
+/// Byte ranges of the words, literals and delimiters of VHDL code, leaving out comments
+fn tokens(code: &str) -> Vec<(usize, usize)> {
+    let bytes = code.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let rest = &code[i..];
+        if rest.starts_with("--") {
+            i += rest.find('\n').unwrap_or(rest.len());
+            continue;
+        } else if rest.starts_with("/*") {
+            i += rest.find("*/").map_or(rest.len(), |end| end + 2);
+            continue;
+        }
+        match bytes[i] {
+            // strings and extended identifiers, a doubled delimiter just starts another one
+            delim @ (b'"' | b'\\') => i += 1 + rest[1..].find(delim as char).map_or(rest.len() - 1, |end| end + 1),
+            b'\'' if bytes.get(i + 2) == Some(&b'\'') => i += 3,
+            c if c.is_ascii_alphanumeric() || c == b'_' => {
+                i += rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            }
+            c if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            _ => i += rest.chars().next().map_or(1, char::len_utf8),
+        }
+        tokens.push((start, i));
+    }
+    tokens
+}
+
+/// Parse a design file, failing on syntax errors
+pub(crate) fn parse(source: &Source) -> Result<ast::DesignFile, CodeError> {
+    let mut diag: Vec<Diagnostic> = Vec::new();
+    let parser = VHDLParser::default();
+    let file = parser.parse_design_source(source, &mut diag);
+    let errors: Vec<String> = diag.iter()
+        .filter(|d| d.severity == Severity::Error)
+        .map(|d| d.show())
+        .collect();
+    if errors.is_empty() {
+        Ok(file)
+    } else {
+        Err(ErrorKind::Compile(errors.join("\n")).into())
+    }
+}
+
+/// Convert all entity declarations in a parsed design file
+pub fn entities(file: &ast::DesignFile) -> Vec<Entity> {
+    file.design_units.iter().filter_map(|unit| match unit {
+        ast::AnyDesignUnit::Primary(ast::AnyPrimaryUnit::Entity(decl)) => Some(entity(decl)),
+        _ => None,
+    }).collect()
+}
+
+/// Convert an entity declaration to an Entity without architectures
+pub fn entity(decl: &ast::EntityDeclaration) -> Entity {
+    let objects = |clause: &Option<Vec<ast::InterfaceDeclaration>>| -> Vec<ast::InterfaceObjectDeclaration> {
+        clause.iter().flatten().filter_map(|item| match item {
+            ast::InterfaceDeclaration::Object(obj) => Some(obj.clone()),
+            _ => None,
+        }).collect()
+    };
+    Entity {
+        name: decl.ident.item.name_utf8(),
+        symbol: Symbol {},
+        generic: objects(&decl.generic_clause).iter().map(generic).collect(),
+        port: objects(&decl.port_clause).iter().map(port).collect(),
+        archs: IndexMap::new(),
+    }
+}
+
+/// Convert all configuration declarations in a parsed design file, by name
+pub fn configurations<S: Simulator + Default>(file: &ast::DesignFile, lib: &Library) -> Result<IndexMap<String, Configuration<S>>, CodeError> {
+    let mut confs = IndexMap::new();
+    for unit in &file.design_units {
+        if let ast::AnyDesignUnit::Primary(ast::AnyPrimaryUnit::Configuration(decl)) = unit {
+            confs.insert(decl.ident.item.name_utf8(), configuration(decl, lib)?);
+        }
+    }
+    Ok(confs)
+}
+
+/// Convert a configuration declaration.
+/// `others` and `all` clauses are expanded to the matching instances of the schematic they configure.
+pub fn configuration<S: Simulator + Default>(decl: &ast::ConfigurationDeclaration, lib: &Library) -> Result<Configuration<S>, CodeError> {
+    let mut conf = Configuration::new(S::default(), find_entity(lib, &decl.entity_name.to_string())?);
+    block(&mut conf, &decl.block_config, lib)?;
+    Ok(conf)
+}
+
+/// VHDL is case insensitive, and the entity may be qualified with its library
+fn find_entity(lib: &Library, name: &str) -> Result<EntityRef, CodeError> {
+    let name = name.rsplit('.').next().unwrap_or_default();
+    lib.entities()
+        .find(|ent| ent.name.eq_ignore_ascii_case(name))
+        .map(|ent| ent.clone().into())
+        .ok_or_else(|| ErrorKind::NoEntity(name.into()).into())
+}
+
+fn find_key<'a, T>(map: &'a IndexMap<String, T>, name: &str) -> Option<&'a String> {
+    map.keys().find(|key| key.eq_ignore_ascii_case(name))
+}
+
+/// Apply a `for ARCH ... end for` block to the configuration of its entity
+fn block<S: Simulator + Default>(conf: &mut Configuration<S>, config: &ast::BlockConfiguration, lib: &Library) -> Result<(), CodeError> {
+    let ent = conf.ent.get()?.clone();
+    let arch_name = config.block_spec.to_string();
+    let arch = find_key(&ent.archs, &arch_name)
+        .ok_or_else(|| ErrorKind::NoArch {entity: ent.name.clone(), arch: arch_name.clone()})?;
+    conf.arch = Some(arch.clone());
+    let sch = match &ent.archs[arch] {
+        Arch::Schematic(sch) => Some(sch),
+        Arch::Code(_) => None,
+    };
+    for item in &config.items {
+        let comp = match item {
+            ast::ConfigurationItem::Component(comp) => comp,
+            ast::ConfigurationItem::Block(_) => return Err(ErrorKind::Unsupported(format!("nested block configuration in {}", arch_name)).into()),
+        };
+        let comp_name = comp.spec.component_name.to_string();
+        let schematic = || sch.ok_or_else(|| ErrorKind::Compile(format!("{} of {} is not a schematic", arch_name, ent.name)));
+        let instances_of = |sch: &'_ Schematic| -> Vec<String> {
+            sch.instances.iter()
+                .filter(|(_, inst)| inst.entity.name().eq_ignore_ascii_case(&comp_name))
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+        let labels = match &comp.spec.instantiation_list {
+            ast::InstantiationList::Labels(labels) => labels.iter().map(|label| {
+                let label = label.item.name_utf8();
+                sch.and_then(|sch| find_key(&sch.instances, &label)).cloned().unwrap_or(label)
+            }).collect(),
+            ast::InstantiationList::All => instances_of(schematic()?),
+            ast::InstantiationList::Others => instances_of(schematic()?).into_iter()
+                .filter(|name| !conf.for_inst.contains_key(name))
+                .collect(),
+        };
+        for label in labels {
+            let mut sub = binding(comp, &comp_name, lib)?;
+            if let Some(inner) = &comp.block_config {
+                block(&mut sub, inner, lib)?;
+            }
+            conf.for_inst.insert(label, sub);
+        }
+    }
+    Ok(())
+}
+
+/// The configuration for the entity and architecture bound to a component.
+/// Without binding indication, the entity of the same name is used.
+fn binding<S: Simulator + Default>(comp: &ast::ComponentConfiguration, comp_name: &str, lib: &Library) -> Result<Configuration<S>, CodeError> {
+    let aspect = comp.bind_ind.as_ref().and_then(|bind| bind.entity_aspect.as_ref());
+    let (ent, arch) = match aspect {
+        None => (find_entity(lib, comp_name)?, None),
+        Some(ast::EntityAspect::Entity(name, arch)) => {
+            let ent = find_entity(lib, &name.to_string())?;
+            let arch = match arch {
+                Some(arch) => {
+                    let arch = arch.item.name_utf8();
+                    let key = find_key(&ent.get()?.archs, &arch)
+                        .ok_or_else(|| ErrorKind::NoArch {entity: ent.name().into(), arch: arch.clone()})?;
+                    Some(key.clone())
+                }
+                None => None,
+            };
+            (ent, arch)
+        }
+        Some(ast::EntityAspect::Configuration(name)) => return Err(ErrorKind::Unsupported(format!("binding {} to configuration {} is not supported", comp_name, name)).into()),
+        Some(ast::EntityAspect::Open) => return Err(ErrorKind::Unsupported(format!("open binding of {} is not supported", comp_name)).into()),
+    };
+    // the actuals of the binding are the ports and generics of the component
+    let bound = Some(ent.get()?.as_ref());
+    let component = lib.entities().find(|ent| ent.name.eq_ignore_ascii_case(comp_name)).map(Rc::as_ref);
+    let ports = |ent: Option<&Entity>| -> Vec<String> { ent.iter().flat_map(|ent| &ent.port).map(|p| p.name.clone()).collect() };
+    let generics = |ent: Option<&Entity>| -> Vec<String> { ent.iter().flat_map(|ent| &ent.generic).map(|g| g.name.clone()).collect() };
+    let bind = comp.bind_ind.as_ref();
+    Ok(Configuration {
+        portmap: association(bind.and_then(|b| b.port_map.as_ref()), &ports(bound), &ports(component))?,
+        genericmap: association(bind.and_then(|b| b.generic_map.as_ref()), &generics(bound), &generics(component))?,
+        arch,
+        ..Configuration::new(S::default(), ent)
+    })
+}
+
+/// Convert a port or generic map of a binding indication, from the formals of the bound entity
+/// to the actuals of the component. Open associations are left out.
+fn association(elems: Option<&Vec<ast::AssociationElement>>, formals: &[String], actuals: &[String]) -> Result<IndexMap<String, String>, CodeError> {
+    let mut map = IndexMap::new();
+    for (i, elem) in elems.into_iter().flatten().enumerate() {
+        let formal = match &elem.formal {
+            Some(formal) => {
+                let formal = formal.to_string();
+                formals.iter().find(|f| f.eq_ignore_ascii_case(&formal)).cloned()
+                    .ok_or_else(|| ErrorKind::Compile(format!("no formal {} in binding", formal)))?
+            }
+            None => formals.get(i).cloned()
+                .ok_or_else(|| ErrorKind::Compile(format!("too many actuals in binding, expected {}", formals.len())))?,
+        };
+        if let ast::ActualPart::Expression(expr) = &elem.actual.item {
+            let actual = expr.to_string();
+            // anything else than a port or generic of the component is a literal
+            let actual = actuals.iter().find(|a| a.eq_ignore_ascii_case(&actual)).cloned().unwrap_or_else(|| format!("\"{}\"", actual));
+            map.insert(formal, actual);
+        }
+    }
+    Ok(map)
+}
+
+/// The unqualified, lowercase name of a type, like `std_logic_vector` for `ieee.std_logic_1164.std_logic_vector`
+fn type_name(subtype: &ast::SubtypeIndication) -> String {
+    let name = subtype.type_mark.to_string().to_lowercase();
+    name.rsplit('.').next().unwrap_or_default().to_string()
+}
+
+fn generic(obj: &ast::InterfaceObjectDeclaration) -> Generic {
+    let (kind, min) = match type_name(&obj.subtype_indication).as_str() {
+        "integer" => (GenericKind::Integer, None),
+        "natural" => (GenericKind::Integer, Some(0.0)),
+        "positive" => (GenericKind::Integer, Some(1.0)),
+        "real" => (GenericKind::Real, None),
+        _ => (GenericKind::String, None),
+    };
+    Generic {
+        name: obj.ident.item.name_utf8(),
+        kind,
+        default: obj.expression.as_ref().map(|expr| expr.to_string()),
+        min,
+        ..Generic::default()
+    }
+}
+
+fn port(obj: &ast::InterfaceObjectDeclaration) -> Port {
+    let direction = match obj.mode {
+        ast::Mode::In => Direction::In,
+        ast::Mode::Out | ast::Mode::Buffer => Direction::Out,
+        ast::Mode::InOut | ast::Mode::Linkage => Direction::InOut,
+    };
+    let typ = type_name(&obj.subtype_indication);
+    let nature = match typ.as_str() {
+        "std_logic" | "std_ulogic" | "std_logic_vector" | "std_ulogic_vector"
+        | "bit" | "bit_vector" | "boolean" | "signed" | "unsigned" => Nature::Logic,
+        _ => Nature::Custom(typ),
+    };
+    let range = obj.subtype_indication.constraint.as_ref().and_then(|constraint| match &constraint.item {
+        ast::SubtypeConstraint::Array(ranges, _) => match ranges.as_slice() {
+            [ast::DiscreteRange::Range(ast::Range::Range(range))] => Some(BusRange {
+                left: range.left_expr.to_string(),
+                direction: match range.direction {
+                    ast::Direction::Ascending => RangeDirection::To,
+                    ast::Direction::Descending => RangeDirection::Downto,
+                },
+                right: range.right_expr.to_string(),
+            }),
+            _ => None,
+        },
+        _ => None,
+    });
+    Port {
+        name: obj.ident.item.name_utf8(),
+        direction,
+        nature,
+        range,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parity() {
+        let ents = parse_entities(Path::new("data/ent.vhdl")).unwrap();
+        assert_eq!(ents.len(), 1);
+        let parity = &ents[0];
+        assert_eq!(parity.name, "PARITY");
+        assert_eq!(parity.generic.len(), 1);
+        assert_eq!(parity.generic[0].name, "N");
+        assert_eq!(parity.generic[0].kind, GenericKind::Integer);
+        assert_eq!(parity.port[0].name, "A");
+        assert_eq!(parity.port[0].direction, Direction::In);
+        assert_eq!(parity.port[0].nature, Nature::Logic);
+        let range = parity.port[0].range.as_ref().unwrap();
+        assert_eq!(range.direction, RangeDirection::Downto);
+        assert_eq!(range.indices(&collection!{"N".into() => "4".into()}), Ok(vec![3, 2, 1, 0]));
+        assert_eq!(parity.port[1].name, "ODD");
+        assert_eq!(parity.port[1].direction, Direction::Out);
+        assert_eq!(parity.port[1].range, None);
+    }
+
+    #[test]
+    fn defaults() {
+        let ents = parse_entities_str("
+            entity dac is
+              generic (bits : positive := 8; vref : real := 1.8; name : string := \"dac\");
+              port (d : in bit_vector(0 to bits-1); clk : in std_logic; vout : out real);
+            end dac;").unwrap();
+        let dac = &ents[0];
+        assert_eq!(dac.generic[0].default.as_deref(), Some("8"));
+        assert_eq!(dac.generic[0].min, Some(1.0));
+        assert_eq!(dac.generic[1].kind, GenericKind::Real);
+        assert_eq!(dac.generic[2].kind, GenericKind::String);
+        assert_eq!(dac.port[0].range.as_ref().unwrap().indices(&dac.generic_defaults()).unwrap().len(), 8);
+        assert_eq!(dac.port[2].nature, Nature::Custom("real".into()));
+        assert!(parse_entities_str("entity broken is").is_err());
+    }
+
+    fn cell(name: &str, ports: &[&str], archs: IndexMap<String, Arch>) -> Entity {
+        crate::tests::entity(name, Vec::new(), ports.iter().map(|&p| p.into()).collect(), archs)
+    }
+
+    fn code(reference: &str) -> Arch {
+        crate::tests::code("spice", Definition::Primitive, reference)
+    }
+
+    fn instance(entity: &str, portmap: IndexMap<String, String>) -> Instance {
+        crate::tests::instance(entity, IndexMap::new(), portmap)
+    }
+
+    /// The full adder from data/conf.vhdl, made of two half adders and an or gate
+    pub(crate) fn fulladder() -> Library {
+        let mut lib = Library::new();
+        let ports = ["A", "B", "SUM", "CARRY"];
+        lib.insert(cell("HALFADDER", &ports, collection!{"rtl".into() => code("xha{{name}} {{port.A}} {{port.B}} {{port.SUM}} {{port.CARRY}} ha")})).unwrap();
+        lib.insert(cell("HA1", &ports, collection!{"RTL".into() => code("xha1{{name}} {{port.A}} {{port.B}} {{port.SUM}} {{port.CARRY}} ha1")})).unwrap();
+        lib.insert(cell("HA2", &["U", "V", "X", "Y"], collection!{
+            "BEHAV".into() => code("xha2b{{name}} {{port.U}} {{port.V}} {{port.X}} {{port.Y}} ha2"),
+            "GATE".into() => code("xha2{{name}} {{port.U}} {{port.V}} {{port.X}} {{port.Y}} ha2"),
+        })).unwrap();
+        lib.insert(cell("OR2", &["A", "B", "Y"], collection!{"rtl".into() => code("xor{{name}} {{port.A}} {{port.B}} {{port.Y}} or2")})).unwrap();
+        let sch = Schematic {
+            toplevel: false,
+            level: None,
+            instances: collection!{
+                "MODULE1".into() => instance("HALFADDER", collection!{"A".into() => "A".into(), "B".into() => "B".into(), "SUM".into() => "s1".into(), "CARRY".into() => "c1".into()}),
+                "MODULE2".into() => instance("HALFADDER", collection!{"A".into() => "s1".into(), "B".into() => "CIN".into(), "SUM".into() => "SUM".into(), "CARRY".into() => "c2".into()}),
+                "MODULE3".into() => instance("OR2", collection!{"A".into() => "c1".into(), "B".into() => "c2".into(), "Y".into() => "COUT".into()}),
+            },
+        };
+        lib.insert(cell("FULLADDER", &["A", "B", "CIN", "SUM", "COUT"], collection!{"STRUCT".into() => Arch::Schematic(sch)})).unwrap();
+        lib
+    }
+
+    #[test]
+    fn fulladder_conf() {
+        let lib = fulladder();
+        let confs = parse_configurations::<Ngspice>(Path::new("data/conf.vhdl"), &lib).unwrap();
+        let conf = &confs["CFG_FULLADDER"];
+        assert_eq!(conf.ent.name(), "FULLADDER");
+        assert_eq!(conf.arch.as_deref(), Some("STRUCT"));
+        let for_inst = &conf.for_inst;
+        assert_eq!(for_inst["MODULE2"].ent.name(), "HA2");
+        assert_eq!(for_inst["MODULE2"].arch.as_deref(), Some("GATE"));
+        assert_eq!(for_inst["MODULE2"].portmap["U"], "A");
+        assert_eq!(for_inst["MODULE2"].portmap["Y"], "CARRY");
+        assert_eq!(for_inst["MODULE1"].ent.name(), "HA1");
+        assert_eq!(for_inst["MODULE1"].arch.as_deref(), Some("RTL"));
+        assert!(!for_inst.contains_key("MODULE3"));
+        let netlist = ".subckt FULLADDER A B CIN SUM COUT\n\
+            xha1MODULE1 A B s1 c1 ha1\n\
+            xha2MODULE2 s1 CIN SUM c2 ha2\n\
+            xorMODULE3 c1 c2 COUT or2\n\
+            .ends FULLADDER";
+        assert!(conf.definition().unwrap().contains(&Definition::Code(netlist.into())));
+
+        let confs = parse_configurations_str::<Ngspice>("
+            configuration all_gates of fulladder is
+              for struct
+                for all: halfadder use entity work.ha2(gate); end for;
+                for module3: or2 end for;
+              end for;
+            end all_gates;", &lib).unwrap();
+        let for_inst = &confs["all_gates"].for_inst;
+        assert_eq!(for_inst["MODULE1"].ent.name(), "HA2");
+        assert_eq!(for_inst["MODULE2"].ent.name(), "HA2");
+        assert_eq!(for_inst["MODULE3"].ent.name(), "OR2");
+        assert_eq!(for_inst["MODULE3"].arch, None);
+
+        // a map right after a block header is no stray semicolon
+        let block = "
+            architecture struct of fulladder is
+            begin
+              inner: block
+                generic (g : integer); generic map (g => 1); -- use entity work.ha2; port map
+              begin
+              end block;
+            end struct;
+            configuration one of fulladder is
+              for struct
+                for module2: halfadder use entity work.ha2(gate); -- \"; port map\"
+                  port map (U => A, V => B, X => SUM, Y => CARRY);
+                end for;
+              end for;
+            end one;";
+        let repaired = binding_semicolons(block);
+        assert!(repaired.contains("generic (g : integer); generic map (g => 1); -- use entity work.ha2; port map"));
+        assert!(repaired.contains("use entity work.ha2(gate) -- \"; port map\""));
+        assert_eq!(parse_configurations_str::<Ngspice>(block, &lib).unwrap()["one"].for_inst["MODULE2"].portmap["Y"], "CARRY");
+
+        assert!(parse_configurations_str::<Ngspice>("
+            configuration bad of fulladder is
+              for struct
+                for all: halfadder use entity work.ha3; end for;
+              end for;
+            end bad;", &lib).is_err());
+    }
+}
@@ -0,0 +1,361 @@
+//! Simulation results, as named signals sampled at the values of a scale such as time or frequency.
+//!
+//! Simulators stream their results in chunks, which are datasets themselves
+//! that are appended to the ones before.
+
+use std::ops::Range;
+use indexmap::IndexMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+/// The samples of a signal
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signal {
+    Real(Vec<f64>),
+    Complex(Vec<Complex>),
+    Digital(Vec<bool>),
+    /// A bus of up to 64 bits, with the most significant bit first in its name
+    Bus { width: usize, values: Vec<u64> },
+}
+
+impl Signal {
+    pub fn len(&self) -> usize {
+        match self {
+            Signal::Real(data) => data.len(),
+            Signal::Complex(data) => data.len(),
+            Signal::Digital(data) => data.len(),
+            Signal::Bus {values, ..} => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_real(&self) -> Option<&[f64]> {
+        match self {
+            Signal::Real(data) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// The samples in a range of indices
+    pub fn slice(&self, range: Range<usize>) -> Signal {
+        match self {
+            Signal::Real(data) => Signal::Real(data[range].to_vec()),
+            Signal::Complex(data) => Signal::Complex(data[range].to_vec()),
+            Signal::Digital(data) => Signal::Digital(data[range].to_vec()),
+            Signal::Bus {width, values} => Signal::Bus {width: *width, values: values[range].to_vec()},
+        }
+    }
+
+    /// Whether the samples of the other signal can be appended to this one
+    pub fn same_kind(&self, other: &Signal) -> bool {
+        match (self, other) {
+            (Signal::Real(_), Signal::Real(_)) | (Signal::Complex(_), Signal::Complex(_)) | (Signal::Digital(_), Signal::Digital(_)) => true,
+            (Signal::Bus {width, ..}, Signal::Bus {width: other_width, ..}) => width == other_width,
+            _ => false,
+        }
+    }
+
+    /// Add the samples of the next chunk, which must be of the same kind
+    pub fn append(&mut self, other: Signal) -> Result<(), String> {
+        match (self, other) {
+            (Signal::Real(data), Signal::Real(more)) => data.extend(more),
+            (Signal::Complex(data), Signal::Complex(more)) => data.extend(more),
+            (Signal::Digital(data), Signal::Digital(more)) => data.extend(more),
+            (Signal::Bus {width, values}, Signal::Bus {width: more_width, values: more}) if *width == more_width => values.extend(more),
+            _ => return Err("signals of a different kind".into()),
+        }
+        Ok(())
+    }
+
+    /// The signal at other values of its scale, which must be increasing.
+    /// Analog signals are interpolated linearly and digital signals hold their value,
+    /// and before or after the scale the first or last sample is taken.
+    pub fn resample(&self, scale: &[f64], at: &[f64]) -> Signal {
+        // the sample at or before each point, and how far the point is towards the next one
+        let points: Vec<(usize, f64)> = at.iter().map(|&x| {
+            let next = scale.partition_point(|&s| s <= x);
+            match next {
+                0 => (0, 0.0),
+                n if n >= scale.len() => (scale.len() - 1, 0.0),
+                n => (n - 1, (x - scale[n - 1]) / (scale[n] - scale[n - 1])),
+            }
+        }).collect();
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        match self {
+            Signal::Real(data) => Signal::Real(points.iter()
+                .map(|&(i, t)| if t > 0.0 { lerp(data[i], data[i + 1], t) } else { data[i] })
+                .collect()),
+            Signal::Complex(data) => Signal::Complex(points.iter()
+                .map(|&(i, t)| if t > 0.0 {
+                    Complex {re: lerp(data[i].re, data[i + 1].re, t), im: lerp(data[i].im, data[i + 1].im, t)}
+                } else {
+                    data[i]
+                })
+                .collect()),
+            Signal::Digital(data) => Signal::Digital(points.iter().map(|&(i, _)| data[i]).collect()),
+            Signal::Bus {width, values} => Signal::Bus {width: *width, values: points.iter().map(|&(i, _)| values[i]).collect()},
+        }
+    }
+}
+
+/// The physical quantity of a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Volt,
+    Ampere,
+    Second,
+    Hertz,
+    None,
+}
+
+impl Unit {
+    /// The unit of a signal from the way SPICE simulators name them.
+    /// `V(out)` and plain nodes are voltages, `I(v1)`, `v1#branch` and device currents
+    /// like `@m1[id]` are currents, and `time` and `frequency` are the usual scales.
+    pub fn infer(name: &str) -> Unit {
+        let name = name.to_lowercase();
+        if name == "time" {
+            Unit::Second
+        } else if name == "frequency" || name == "freq" {
+            Unit::Hertz
+        } else if name.starts_with("v(") {
+            Unit::Volt
+        } else if name.starts_with("i(") || name.ends_with("#branch") {
+            Unit::Ampere
+        } else if let Some(param) = name.strip_prefix('@').and_then(|dev| dev.split_once('[')).map(|(_, param)| param) {
+            match param.chars().next() {
+                Some('i') => Unit::Ampere,
+                Some('v') => Unit::Volt,
+                _ => Unit::None,
+            }
+        } else {
+            Unit::Volt
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::Volt => "V",
+            Unit::Ampere => "A",
+            Unit::Second => "s",
+            Unit::Hertz => "Hz",
+            Unit::None => "",
+        }
+    }
+}
+
+/// Signals with the same number of samples, one of which is the scale of the others
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Dataset {
+    scale: String,
+    signals: IndexMap<String, Signal>,
+}
+
+impl Dataset {
+    pub fn new(scale: &str) -> Dataset {
+        Dataset {scale: scale.into(), signals: IndexMap::new()}
+    }
+
+    /// The name of the scale
+    pub fn scale(&self) -> &str {
+        &self.scale
+    }
+
+    pub fn signals(&self) -> &IndexMap<String, Signal> {
+        &self.signals
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Signal> {
+        self.signals.get(name)
+    }
+
+    /// Add or replace a signal, which must have as many samples as the others
+    pub fn insert(&mut self, name: &str, signal: Signal) -> Result<(), String> {
+        if let Some((other, len)) = self.signals.iter().find(|(other, _)| *other != name).map(|(other, s)| (other, s.len())) {
+            if signal.len() != len {
+                return Err(format!("{} has {} samples, but {} has {}", name, signal.len(), other, len));
+            }
+        }
+        self.signals.insert(name.into(), signal);
+        Ok(())
+    }
+
+    /// The unit of a signal of the dataset, inferred from its name
+    pub fn unit(&self, name: &str) -> Option<Unit> {
+        self.signals.get(name).map(|_| Unit::infer(name))
+    }
+
+    /// The number of samples
+    pub fn len(&self) -> usize {
+        self.signals.get(&self.scale).map_or(0, Signal::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The values of the scale. A complex scale, as the frequency of an AC analysis, gives its real part.
+    pub fn scale_values(&self) -> Result<Vec<f64>, String> {
+        match self.signals.get(&self.scale) {
+            Some(Signal::Real(data)) => Ok(data.clone()),
+            Some(Signal::Complex(data)) => Ok(data.iter().map(|c| c.re).collect()),
+            Some(_) => Err(format!("scale {} is not analog", self.scale)),
+            None => Err(format!("no scale {}", self.scale)),
+        }
+    }
+
+    /// The samples in a range of indices
+    pub fn slice(&self, range: Range<usize>) -> Dataset {
+        Dataset {
+            scale: self.scale.clone(),
+            signals: self.signals.iter().map(|(name, signal)| (name.clone(), signal.slice(range.clone()))).collect(),
+        }
+    }
+
+    /// The samples with a scale value from `start` up to and including `stop`
+    pub fn window(&self, start: f64, stop: f64) -> Result<Dataset, String> {
+        let scale = self.scale_values()?;
+        let from = scale.partition_point(|&s| s < start);
+        let to = scale.partition_point(|&s| s <= stop).max(from);
+        Ok(self.slice(from..to))
+    }
+
+    /// All signals at other values of the scale, such as a common time base for
+    /// results with different time steps
+    pub fn resample(&self, at: &[f64]) -> Result<Dataset, String> {
+        let scale = self.scale_values()?;
+        if scale.is_empty() {
+            return Err("no samples to resample".into());
+        }
+        let mut res = Dataset::new(&self.scale);
+        for (name, signal) in &self.signals {
+            if *name == self.scale {
+                res.insert(name, Signal::Real(at.to_vec()))?;
+            } else {
+                res.insert(name, signal.resample(&scale, at))?;
+            }
+        }
+        Ok(res)
+    }
+
+    /// Add the next chunk of the same analysis.
+    /// The chunk is checked as a whole first, so on an error the dataset is left as it was.
+    pub fn append(&mut self, chunk: Dataset) -> Result<(), String> {
+        if self.signals.is_empty() {
+            *self = chunk;
+            return Ok(());
+        }
+        if chunk.scale != self.scale || chunk.signals.len() != self.signals.len() {
+            return Err("chunk of a different analysis".into());
+        }
+        for (name, more) in &chunk.signals {
+            match self.signals.get(name) {
+                Some(signal) if signal.same_kind(more) => (),
+                Some(_) => return Err(format!("signals of a different kind of {}", name)),
+                None => return Err(format!("no signal {} before this chunk", name)),
+            }
+        }
+        for (name, more) in chunk.signals {
+            self.signals[&name].append(more)?;
+        }
+        Ok(())
+    }
+
+    /// Combine digital signals into a bus, with the most significant bit first
+    pub fn bus(&mut self, name: &str, bits: &[&str]) -> Result<(), String> {
+        if bits.len() > 64 {
+            return Err(format!("bus {} of {} bits is too wide", name, bits.len()));
+        }
+        let mut values = vec![0u64; self.len()];
+        for bit in bits {
+            match self.signals.get(*bit) {
+                Some(Signal::Digital(data)) if data.len() == values.len() => {
+                    for (value, &b) in values.iter_mut().zip(data) {
+                        *value = *value << 1 | b as u64;
+                    }
+                }
+                Some(_) => return Err(format!("{} is not a digital signal of the dataset", bit)),
+                None => return Err(format!("no signal {}", bit)),
+            }
+        }
+        self.insert(name, Signal::Bus {width: bits.len(), values})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tran() -> Dataset {
+        let mut data = Dataset::new("time");
+        data.insert("time", Signal::Real(vec![0.0, 1.0, 2.0, 4.0])).unwrap();
+        data.insert("out", Signal::Real(vec![0.0, 1.0, 3.0, 3.0])).unwrap();
+        data.insert("clk", Signal::Digital(vec![false, true, false, true])).unwrap();
+        data.insert("q", Signal::Digital(vec![true, false, true, true])).unwrap();
+        data
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(Unit::infer("time"), Unit::Second);
+        assert_eq!(Unit::infer("frequency"), Unit::Hertz);
+        assert_eq!(Unit::infer("V(OUT)"), Unit::Volt);
+        assert_eq!(Unit::infer("out"), Unit::Volt);
+        assert_eq!(Unit::infer("I(V1)"), Unit::Ampere);
+        assert_eq!(Unit::infer("vdd#branch"), Unit::Ampere);
+        assert_eq!(Unit::infer("@m.xinv1.mnmos[id]"), Unit::Ampere);
+        assert_eq!(Unit::infer("@m1[vdsat]"), Unit::Volt);
+        assert_eq!(Unit::infer("@m1[gm]"), Unit::None);
+        assert_eq!(Unit::infer("I(V1)").symbol(), "A");
+        let mut data = Dataset::new("time");
+        data.insert("time", Signal::Real(vec![0.0])).unwrap();
+        data.insert("I(V1)", Signal::Real(vec![1e-3])).unwrap();
+        assert_eq!(data.unit("I(V1)"), Some(Unit::Ampere));
+        assert_eq!(data.unit("V(OUT)"), None);
+    }
+
+    #[test]
+    fn chunks() {
+        let mut data = Dataset::default();
+        data.append(tran().slice(0..2)).unwrap();
+        data.append(tran().slice(2..4)).unwrap();
+        assert_eq!(data, tran());
+        let mut other = Dataset::new("frequency");
+        other.insert("frequency", Signal::Real(vec![1.0])).unwrap();
+        assert!(data.append(other).is_err());
+        // a mismatch in a later signal leaves the earlier ones as they were
+        let mut chunk = tran();
+        chunk.insert("q", Signal::Complex(vec![Complex::default(); 4])).unwrap();
+        assert!(data.append(chunk).is_err());
+        assert_eq!(data, tran());
+        // every signal has as many samples as the others
+        assert!(data.insert("out", Signal::Real(vec![0.0; 3])).is_err());
+        assert!(data.insert("out", Signal::Real(vec![0.0; 4])).is_ok());
+    }
+
+    #[test]
+    fn window_and_resample() {
+        let data = tran();
+        assert_eq!(data.window(1.0, 2.5).unwrap(), data.slice(1..3));
+        assert!(data.window(5.0, 6.0).unwrap().is_empty());
+        let even = data.resample(&[-1.0, 0.5, 1.0, 3.0, 5.0]).unwrap();
+        assert_eq!(even.get("time"), Some(&Signal::Real(vec![-1.0, 0.5, 1.0, 3.0, 5.0])));
+        assert_eq!(even.get("out"), Some(&Signal::Real(vec![0.0, 0.5, 1.0, 3.0, 3.0])));
+        assert_eq!(even.get("clk"), Some(&Signal::Digital(vec![false, false, true, false, true])));
+    }
+
+    #[test]
+    fn bus() {
+        let mut data = tran();
+        data.bus("count", &["clk", "q"]).unwrap();
+        assert_eq!(data.get("count"), Some(&Signal::Bus {width: 2, values: vec![1, 2, 1, 3]}));
+        assert!(data.bus("bad", &["clk", "out"]).is_err());
+    }
+}